@@ -1,6 +1,7 @@
 use arg::Args;
 use arg::ParseError;
 use std::env;
+use std::process::ExitCode;
 
 #[derive(Args)]
 pub struct TracerArgs {
@@ -21,17 +22,49 @@ pub struct TracerArgs {
 
     #[arg(short = "o", long = "output")]
     pub output_path: Option<String>,
+
+    #[arg(long = "scene")]
+    pub scene_name: Option<String>,
+
+    #[arg(long = "scene-file")]
+    pub scene_path: Option<String>,
+
+    #[arg(long = "list-scenes")]
+    pub list_scenes: bool,
+
+    #[arg(long = "bvh")]
+    pub bvh_builder: Option<String>,
+
+    #[arg(long = "tonemap")]
+    pub tone_mapper: Option<String>,
+
+    #[arg(long = "exposure", default_value = "0.0")]
+    pub exposure: f64,
+
+    #[arg(long = "exr-float")]
+    pub exr_float: bool,
+
+    #[arg(long = "format")]
+    pub format: Option<String>,
+
+    #[arg(long = "jpeg-quality", default_value = "90")]
+    pub jpeg_quality: u8,
 }
 
 impl TracerArgs {
-    pub fn from_std() -> Option<TracerArgs> {
+    // Fails with the code to exit with, a success when only the help was asked
+    // for.
+    pub fn from_std() -> Result<TracerArgs, ExitCode> {
         let raw_args: Vec<String> = env::args().collect();
         let arguments = TracerArgs::from_args(raw_args.iter().skip(1).map(String::as_str));
         match arguments {
             Err(error) => {
                 use ParseError::*;
                 match error {
-                    HelpRequested(message) => eprintln!("{}", message),
+                    HelpRequested(message) => {
+                        eprintln!("{}", message);
+                        return Err(ExitCode::SUCCESS);
+                    }
                     UnknownFlag(message) => eprintln!("Unknown flag {}", message),
                     TooManyArgs => eprintln!("Too many args!"),
                     RequiredArgMissing(arg) => eprintln!("the argument {} is missing", arg),
@@ -41,9 +74,9 @@ impl TracerArgs {
                     }
                     InvalidArgValue(arg, value) => eprintln!("Invalid value {} for {}", value, arg),
                 }
-                None
+                Err(ExitCode::FAILURE)
             }
-            Ok(args) => Some(args),
+            Ok(args) => Ok(args),
         }
     }
 }
@@ -0,0 +1,68 @@
+use crate::material::{HenyeyGreenstein, Material};
+use crate::math::*;
+use crate::texture::Texture;
+use crate::{HitRecord, Ray};
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+use std::sync::Arc;
+
+// A homogeneous medium filling the whole scene, camera included, for haze and
+// light shafts. Everything except the bounds, which are only known once the
+// scene's objects are gathered.
+#[derive(Clone)]
+pub struct AtmosphereSettings {
+    pub density: f64,
+    pub albedo: Arc<dyn Texture>,
+    pub anisotropy: f64,
+}
+
+impl AtmosphereSettings {
+    pub fn build(&self, bounds: AABB) -> Atmosphere {
+        Atmosphere {
+            density: self.density,
+            bounds,
+            phase_function: Arc::new(HenyeyGreenstein {
+                albedo: self.albedo.clone(),
+                anisotropy: self.anisotropy,
+            }),
+        }
+    }
+}
+
+// Rays leaving the bounds leave the atmosphere too, so the background can
+// still be reached.
+#[derive(Clone)]
+pub struct Atmosphere {
+    density: f64,
+    bounds: AABB,
+    phase_function: Arc<dyn Material>,
+}
+
+impl Atmosphere {
+    // Returns where the ray scatters in the atmosphere, if it does before
+    // reaching t_max, the closest surface.
+    pub fn scatter_before(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
+        let (t_entry, t_exit) = self.bounds.hit_interval(ray, t_min, t_max)?;
+        let random: f64 = Uniform::from(0.0..1.0).sample(rng);
+        let t = t_entry - (1.0 - random).ln() / (self.density * ray.direction.norm());
+        if t >= t_exit {
+            return None;
+        }
+        Some(HitRecord {
+            t,
+            p: ray.at(t),
+            normal: Vec3::new(1.0, 0.0, 0.0),
+            front_facing: true,
+            material: self.phase_function.as_ref(),
+            u: 0.0,
+            v: 0.0,
+            vertex_color: None,
+        })
+    }
+}
@@ -0,0 +1,427 @@
+use crate::hittable::{collect_lights, Hittable};
+use crate::math::*;
+use crate::{HitRecord, Ray};
+use std::sync::Arc;
+
+// Nodes this small always become leaves.
+const MAX_PRIMITIVES_PER_LEAF: usize = 4;
+// Nodes up to this size become leaves when no split is cheaper.
+const MAX_PRIMITIVES_PER_SAH_LEAF: usize = 16;
+// Past this depth, nodes are split at the median so the traversal stack can't
+// overflow, whatever the SAH picks.
+const MAX_SAH_DEPTH: usize = 64;
+
+// Cost of visiting a node, relative to the cost of intersecting a primitive.
+const TRAVERSAL_COST: f64 = 0.125;
+// Number of buckets the centroids are sorted in to evaluate split costs.
+const SAH_BINS: usize = 16;
+
+pub struct SahSplit {
+    pub axis: usize,
+    // Number of items on the left side.
+    pub mid: usize,
+    // Expected cost of the split, comparable to a leaf's, its item count.
+    pub cost: f64,
+}
+
+// Binned surface area heuristic (Wald, 2007). The centroids are sorted in
+// buckets along every axis and the split between each pair of buckets is
+// costed by the area and item count of both sides. The items are partitioned
+// in place at the cheapest split, or at the median of the longest axis when
+// every centroid falls in the same bucket.
+pub fn sah_partition<T>(items: &mut [T], bbox_of: impl Fn(&T) -> &AABB) -> SahSplit {
+    let count = items.len();
+    let mut bounds = bbox_of(&items[0]).clone();
+    let mut centroid_min = Vec3::from_element(f64::INFINITY);
+    let mut centroid_max = Vec3::from_element(f64::NEG_INFINITY);
+    for item in items.iter() {
+        let bbox = bbox_of(item);
+        bounds = bounds.union(bbox);
+        centroid_min = vmin(&centroid_min, &bbox.centroid());
+        centroid_max = vmax(&centroid_max, &bbox.centroid());
+    }
+    let parent_area = bounds.surface_area().max(f64::MIN_POSITIVE);
+    let extent = centroid_max - centroid_min;
+    let bin_of = |item: &T, axis: usize| {
+        let offset = bbox_of(item).centroid()[axis] - centroid_min[axis];
+        ((offset * SAH_BINS as f64 / extent[axis]) as usize).min(SAH_BINS - 1)
+    };
+
+    // (axis, last bucket on the left, cost)
+    let mut best: Option<(usize, usize, f64)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let mut bin_counts = [0usize; SAH_BINS];
+        let mut bin_bounds: [Option<AABB>; SAH_BINS] = Default::default();
+        for item in items.iter() {
+            let bin = bin_of(item, axis);
+            bin_counts[bin] += 1;
+            let bbox = bbox_of(item);
+            bin_bounds[bin] = Some(match &bin_bounds[bin] {
+                Some(bin_bbox) => bin_bbox.union(bbox),
+                None => bbox.clone(),
+            });
+        }
+
+        // Sweep from the right to know the cost of everything after a bucket.
+        let mut right_costs = [0.0; SAH_BINS];
+        let mut accumulated: Option<AABB> = None;
+        let mut right_count = 0;
+        for bin in (1..SAH_BINS).rev() {
+            accumulated = merge(accumulated, &bin_bounds[bin]);
+            right_count += bin_counts[bin];
+            right_costs[bin] = accumulated
+                .as_ref()
+                .map_or(0.0, |bbox| bbox.surface_area() * right_count as f64);
+        }
+
+        let mut accumulated: Option<AABB> = None;
+        let mut left_count = 0;
+        for bin in 0..SAH_BINS - 1 {
+            accumulated = merge(accumulated, &bin_bounds[bin]);
+            left_count += bin_counts[bin];
+            if left_count == 0 || left_count == count {
+                continue;
+            }
+            let left_cost = accumulated
+                .as_ref()
+                .map_or(0.0, |bbox| bbox.surface_area() * left_count as f64);
+            let cost = TRAVERSAL_COST + (left_cost + right_costs[bin + 1]) / parent_area;
+            if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                best = Some((axis, bin, cost));
+            }
+        }
+    }
+
+    match best {
+        Some((axis, last_left_bin, cost)) => {
+            let mut mid = 0;
+            for index in 0..count {
+                if bin_of(&items[index], axis) <= last_left_bin {
+                    items.swap(index, mid);
+                    mid += 1;
+                }
+            }
+            SahSplit { axis, mid, cost }
+        }
+        None => {
+            let axis = extent.imax();
+            let mid = count / 2;
+            items.select_nth_unstable_by(mid, |a, b| {
+                let a = bbox_of(a).centroid()[axis];
+                let b = bbox_of(b).centroid()[axis];
+                a.total_cmp(&b)
+            });
+            SahSplit {
+                axis,
+                mid,
+                cost: count as f64,
+            }
+        }
+    }
+}
+
+fn merge(accumulated: Option<AABB>, bbox: &Option<AABB>) -> Option<AABB> {
+    match (accumulated, bbox) {
+        (Some(accumulated), Some(bbox)) => Some(accumulated.union(bbox)),
+        (accumulated, None) => accumulated,
+        (None, bbox) => bbox.clone(),
+    }
+}
+
+struct IndexNode {
+    bbox: AABB,
+    // Leaves: index of the first primitive in `indices`. Inner nodes: index of
+    // the second child, the first one being right after its parent.
+    offset: u32,
+    // Zero for inner nodes.
+    count: u32,
+    axis: u8,
+}
+
+// A BVH over the primitives of a single hittable (a mesh's triangles, a point
+// cloud's points...), storing indices so the owner keeps its own data layout.
+pub struct IndexBvh {
+    nodes: Vec<IndexNode>,
+    indices: Vec<u32>,
+}
+
+impl IndexBvh {
+    pub fn new(bboxes: &[AABB]) -> IndexBvh {
+        assert!(!bboxes.is_empty(), "A BVH needs at least one primitive");
+        let mut items: Vec<(AABB, u32)> = bboxes
+            .iter()
+            .enumerate()
+            .map(|(index, bbox)| (bbox.clone(), index as u32))
+            .collect();
+        let mut nodes = vec![];
+        IndexBvh::build_node(&mut items[..], 0, 0, &mut nodes);
+        IndexBvh {
+            nodes,
+            indices: items.into_iter().map(|(_, index)| index).collect(),
+        }
+    }
+
+    // The items get reordered so every leaf covers a contiguous range.
+    fn build_node(
+        items: &mut [(AABB, u32)],
+        first: usize,
+        depth: usize,
+        nodes: &mut Vec<IndexNode>,
+    ) {
+        let bbox = items
+            .iter()
+            .map(|(bbox, _)| bbox.clone())
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+
+        let node_index = nodes.len();
+        nodes.push(IndexNode {
+            bbox,
+            offset: first as u32,
+            count: items.len() as u32,
+            axis: 0,
+        });
+        if items.len() <= MAX_PRIMITIVES_PER_LEAF {
+            return;
+        }
+
+        let (axis, mid) = if depth < MAX_SAH_DEPTH {
+            let split = sah_partition(items, |(bbox, _)| bbox);
+            if split.cost >= items.len() as f64 && items.len() <= MAX_PRIMITIVES_PER_SAH_LEAF {
+                return;
+            }
+            (split.axis, split.mid)
+        } else {
+            let axis = (nodes[node_index].bbox.max - nodes[node_index].bbox.min).imax();
+            let mid = items.len() / 2;
+            items.select_nth_unstable_by(mid, |(a, _), (b, _)| {
+                a.centroid()[axis].total_cmp(&b.centroid()[axis])
+            });
+            (axis, mid)
+        };
+        let (left, right) = items.split_at_mut(mid);
+
+        IndexBvh::build_node(left, first, depth + 1, nodes);
+        let right_index = nodes.len();
+        IndexBvh::build_node(right, first + mid, depth + 1, nodes);
+
+        let node = &mut nodes[node_index];
+        node.offset = right_index as u32;
+        node.count = 0;
+        node.axis = axis as u8;
+    }
+
+    pub fn bounding_box(&self) -> AABB {
+        self.nodes[0].bbox.clone()
+    }
+
+    // Returns the closest hit, hit_primitive being called with a primitive
+    // index and the closest distance found so far.
+    pub fn hit<'a>(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        mut hit_primitive: impl FnMut(usize, f64) -> Option<HitRecord<'a>>,
+    ) -> Option<HitRecord<'a>> {
+        let mut closest: Option<HitRecord> = None;
+        let mut closest_t = t_max;
+        // The build caps the SAH's depth, median splits then keep the
+        // remaining depth around log2(primitives).
+        let mut stack = [0usize; 128];
+        let mut stack_size = 1;
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_index = stack[stack_size];
+            let node = &self.nodes[node_index];
+            if !node.bbox.intersects(ray, t_min, closest_t) {
+                continue;
+            }
+            if node.count > 0 {
+                let first = node.offset as usize;
+                for &index in &self.indices[first..first + node.count as usize] {
+                    if let Some(record) = hit_primitive(index as usize, closest_t) {
+                        closest_t = record.t;
+                        closest = Some(record);
+                    }
+                }
+            } else {
+                // Visit the child closest to the ray's origin first.
+                let (near, far) = if ray.direction[node.axis as usize] < 0.0 {
+                    (node.offset as usize, node_index + 1)
+                } else {
+                    (node_index + 1, node.offset as usize)
+                };
+                stack[stack_size] = far;
+                stack[stack_size + 1] = near;
+                stack_size += 2;
+            }
+        }
+        closest
+    }
+}
+
+// Drop-in replacement for a BvhNode tree: the scene's objects under a single
+// flat, SAH-built IndexBvh, traversed without recursion nor virtual calls until
+// the leaves.
+pub struct FlatBvh {
+    objects: Vec<Arc<dyn Hittable>>,
+    bvh: IndexBvh,
+}
+
+impl FlatBvh {
+    pub fn new(objects: &[Arc<dyn Hittable>], t0: f64, t1: f64) -> FlatBvh {
+        let bboxes: Vec<AABB> = objects
+            .iter()
+            .map(|object| {
+                object.bounding_box(t0, t1).unwrap_or_else(|| {
+                    eprintln!("No bbox in FlatBvh constructor");
+                    AABB::zeros()
+                })
+            })
+            .collect();
+        FlatBvh {
+            objects: objects.to_vec(),
+            bvh: IndexBvh::new(&bboxes),
+        }
+    }
+}
+
+impl Hittable for FlatBvh {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.bvh.hit(ray, t_min, t_max, |index, closest_t| {
+            self.objects[index].hit(ray, t_min, closest_t)
+        })
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        Some(self.bvh.bounding_box())
+    }
+
+    fn lights(&self) -> Vec<Arc<dyn Hittable>> {
+        collect_lights(&self.objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::{BvhNode, HittableList, Sphere};
+    use crate::material::Lambertian;
+    use crate::texture::SolidColor;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn unit_box(x: f64) -> AABB {
+        AABB::new(Vec3::new(x, 0.0, 0.0), Vec3::new(x + 1.0, 1.0, 1.0))
+    }
+
+    // Overlapping spheres of all sizes, some far apart, some moving through
+    // each other's boxes.
+    fn random_spheres(count: usize, rng: &mut SmallRng) -> Vec<Arc<dyn Hittable>> {
+        let material = Arc::new(Lambertian {
+            albedo: Arc::new(SolidColor::new(0.5, 0.5, 0.5)),
+        });
+        (0..count)
+            .map(|_| {
+                Arc::new(Sphere {
+                    center: Vec3::new(
+                        rng.gen_range(-10.0..10.0),
+                        rng.gen_range(-10.0..10.0),
+                        rng.gen_range(-10.0..10.0),
+                    ),
+                    radius: rng.gen_range(0.05..2.0),
+                    material: material.clone(),
+                }) as Arc<dyn Hittable>
+            })
+            .collect()
+    }
+
+    // The BVH finds the same closest hit as testing every object, for rays
+    // starting inside and outside the objects' bounds. Half of them aim at an
+    // object, the others anywhere.
+    fn assert_same_hits(bvh: &dyn Hittable, objects: &[Arc<dyn Hittable>], rng: &mut SmallRng) {
+        let reference = HittableList::from_slice(objects, 0.0, 1.0, rng);
+        let mut hits = 0;
+        for i in 0..2000 {
+            let origin = Vec3::new(
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+            );
+            let target = if i % 2 == 0 {
+                let object = &objects[rng.gen_range(0..objects.len())];
+                object.bounding_box(0.0, 1.0).unwrap().centroid()
+            } else {
+                Vec3::new(
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                )
+            };
+            let ray = Ray {
+                origin,
+                direction: target - origin,
+                time: 0.0,
+            };
+            let expected = reference.hit(&ray, 0.001, f64::INFINITY).map(|r| r.t);
+            let found = bvh.hit(&ray, 0.001, f64::INFINITY).map(|r| r.t);
+            assert_eq!(found, expected, "{:?}", ray.origin);
+            hits += expected.is_some() as usize;
+        }
+        // The rays aimed at objects hit something.
+        assert!(hits >= 1000, "{} hits", hits);
+    }
+
+    #[test]
+    fn sah_splits_between_clusters() {
+        let mut boxes: Vec<AABB> = (0..6).map(|i| unit_box(i as f64 * 0.1)).collect();
+        boxes.extend((0..3).map(|i| unit_box(100.0 + i as f64 * 0.1)));
+        boxes.swap(1, 7);
+        let split = sah_partition(&mut boxes, |bbox| bbox);
+        assert_eq!((split.axis, split.mid), (0, 6));
+        assert!(boxes[..6].iter().all(|bbox| bbox.min.x < 1.0));
+        assert!(boxes[6..].iter().all(|bbox| bbox.min.x >= 100.0));
+        // Two tight halves are much cheaper than a leaf of 9.
+        assert!(split.cost < 9.0);
+    }
+
+    #[test]
+    fn sah_splits_stacked_boxes_at_the_median() {
+        let mut boxes = vec![unit_box(0.0); 7];
+        let split = sah_partition(&mut boxes, |bbox| bbox);
+        assert_eq!((split.mid, split.cost), (3, 7.0));
+    }
+
+    #[test]
+    fn sah_trees_find_the_closest_hits() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        for count in [1, 2, 3, 50, 500] {
+            let objects = random_spheres(count, &mut rng);
+            let sah = BvhNode::from_slice_sah(&objects, 0.0, 1.0);
+            assert_same_hits(&sah, &objects, &mut rng);
+            let random = BvhNode::from_slice(&objects, 0.0, 1.0, &mut rng);
+            assert_same_hits(&random, &objects, &mut rng);
+        }
+    }
+
+    #[test]
+    fn flat_trees_find_the_closest_hits() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        for count in [1, 2, 3, 50, 500] {
+            let objects = random_spheres(count, &mut rng);
+            let flat = FlatBvh::new(&objects, 0.0, 1.0);
+            assert_same_hits(&flat, &objects, &mut rng);
+        }
+        // Identical objects can't be told apart by the SAH and are split at
+        // the median instead.
+        let sphere = random_spheres(1, &mut rng).remove(0);
+        let objects = vec![sphere; 300];
+        let flat = FlatBvh::new(&objects, 0.0, 1.0);
+        assert_same_hits(&flat, &objects, &mut rng);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::math::{vpowf, Vec3};
+use crate::math::Vec3;
 
 // sRGB is *not* BT.709
 // https://en.wikipedia.org/wiki/Rec._709#Relationship_to_sRGB
@@ -12,10 +12,20 @@ pub fn linear_to_srgb(linear: &Vec3) -> Vec3 {
     })
 }
 
+// Relative luminance of a linear color, with the BT.709 primaries sRGB shares.
+pub fn luminance(linear: &Vec3) -> f64 {
+    0.2126 * linear.x + 0.7152 * linear.y + 0.0722 * linear.z
+}
+
 fn offset_limit_to_255(v: f64) -> u8 {
     (v * 255. + 0.5).floor().clamp(0., 255.) as u8
 }
 
+pub fn downscale_to_16bit(color: &Vec3) -> (u16, u16, u16) {
+    let quantize = |v: f64| (v * 65535. + 0.5).floor().clamp(0., 65535.) as u16;
+    (quantize(color.x), quantize(color.y), quantize(color.z))
+}
+
 pub fn downscale_to_8bit(color: &Vec3) -> (u8, u8, u8) {
     // This works and converts into a [0;1] range and slightly works better than
     // v * 255 as it gives relatively more range for extreme values like 0 or 255.
@@ -0,0 +1,283 @@
+use crate::colors::luminance;
+use crate::math::Vec3;
+use image::codecs::hdr::HdrDecoder;
+use image::error::{LimitError, LimitErrorKind};
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+use std::f64::consts::{PI, TAU};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// What rays leaving the scene see, lighting it from infinitely far away.
+//
+// Environments are lights too: random gives a direction towards them and
+// pdf_value the solid angle density of picking a given direction.
+pub trait Environment: Sync + Send {
+    fn radiance(&self, direction: &Vec3) -> Vec3;
+
+    fn pdf_value(&self, direction: &Vec3) -> f64;
+
+    fn random(&self, rng: &mut dyn RngCore) -> Vec3;
+
+    // Luminance summed over the sphere of directions, as Hittable::power is
+    // for the other lights, to pick it as often as it lights the scene.
+    fn power(&self) -> f64;
+}
+
+// An equirectangular (latitude-longitude) picture of the surroundings. Its
+// top row is straight up (+y) and its center faces -z, before being turned
+// around the y axis by rotation radians.
+//
+// Directions are sampled from the texels' luminance, weighed by the solid
+// angle they cover, so a small sun gets the samples it needs.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3>,
+    rotation: f64,
+    intensity: f64,
+    // Running sums of the rows' weights, and of the texels' ones in each row.
+    row_sums: Vec<f64>,
+    column_sums: Vec<f64>,
+}
+
+impl EnvironmentMap {
+    // Radiance .hdr files are kept as they are, other pictures are taken as
+    // sRGB and scaled to [0; 1].
+    pub fn open(path: &Path, rotation: f64, intensity: f64) -> image::ImageResult<EnvironmentMap> {
+        let is_hdr = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+        let (width, height, texels) = if is_hdr {
+            let file = File::open(path).map_err(image::ImageError::IoError)?;
+            let decoder = HdrDecoder::new(BufReader::new(file))?;
+            let metadata = decoder.metadata();
+            let texels = decoder
+                .read_image_hdr()?
+                .iter()
+                .map(|pixel| Vec3::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64))
+                .collect();
+            (metadata.width, metadata.height, texels)
+        } else {
+            let picture = image::open(path)?.to_rgb8();
+            let texels = picture
+                .pixels()
+                .map(|pixel| {
+                    Vec3::new(
+                        (pixel[0] as f64 / 255.0).powf(2.2),
+                        (pixel[1] as f64 / 255.0).powf(2.2),
+                        (pixel[2] as f64 / 255.0).powf(2.2),
+                    )
+                })
+                .collect();
+            (picture.width(), picture.height(), texels)
+        };
+        if width == 0 || height == 0 {
+            return Err(image::ImageError::Limits(LimitError::from_kind(
+                LimitErrorKind::DimensionError,
+            )));
+        }
+        Ok(EnvironmentMap::new(
+            width as usize,
+            height as usize,
+            texels,
+            rotation,
+            intensity,
+        ))
+    }
+
+    pub fn new(
+        width: usize,
+        height: usize,
+        texels: Vec<Vec3>,
+        rotation: f64,
+        intensity: f64,
+    ) -> EnvironmentMap {
+        assert_eq!(texels.len(), width * height, "Wrong texel count");
+        let mut row_sums = Vec::with_capacity(height);
+        let mut column_sums = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for (row, texels) in texels.chunks_exact(width).enumerate() {
+            // Rows near the poles cover less of the sphere.
+            let sin_theta = (PI * (row as f64 + 0.5) / height as f64).sin();
+            let mut row_total = 0.0;
+            for texel in texels {
+                row_total += luminance(texel).max(0.0) * sin_theta;
+                column_sums.push(row_total);
+            }
+            total += row_total;
+            row_sums.push(total);
+        }
+        EnvironmentMap {
+            width,
+            height,
+            texels,
+            rotation,
+            intensity,
+            row_sums,
+            column_sums,
+        }
+    }
+
+    fn total_weight(&self) -> f64 {
+        self.row_sums[self.height - 1]
+    }
+
+    fn texel_weight(&self, row: usize, column: usize) -> f64 {
+        let sums = &self.column_sums[row * self.width..(row + 1) * self.width];
+        // Rounding errors could make black texels slightly negative.
+        match column {
+            0 => sums[0],
+            _ => (sums[column] - sums[column - 1]).max(0.0),
+        }
+    }
+
+    // The texel coordinates, in [0; 1], seen in a direction.
+    fn direction_to_uv(&self, direction: &Vec3) -> (f64, f64) {
+        let direction = direction.normalize();
+        let (sin, cos) = self.rotation.sin_cos();
+        let x = direction.x * cos - direction.z * sin;
+        let z = direction.x * sin + direction.z * cos;
+        let u = 0.5 + x.atan2(-z) / TAU;
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> Vec3 {
+        let (sin_theta, cos_theta) = (PI * v).sin_cos();
+        let (sin_phi, cos_phi) = (TAU * (u - 0.5)).sin_cos();
+        let x = sin_theta * sin_phi;
+        let z = -sin_theta * cos_phi;
+        let (sin, cos) = self.rotation.sin_cos();
+        Vec3::new(x * cos + z * sin, cos_theta, z * cos - x * sin)
+    }
+
+    fn texel_at(&self, u: f64, v: f64) -> (usize, usize) {
+        let column = ((u * self.width as f64) as usize).min(self.width - 1);
+        let row = ((v * self.height as f64) as usize).min(self.height - 1);
+        (row, column)
+    }
+}
+
+// The first index whose running sum goes past a fraction of the total.
+fn pick_from_sums(sums: &[f64], fraction: f64) -> usize {
+    let target = fraction * sums[sums.len() - 1];
+    sums.partition_point(|&sum| sum <= target)
+        .min(sums.len() - 1)
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let (u, v) = self.direction_to_uv(direction);
+        let (row, column) = self.texel_at(u, v);
+        self.texels[row * self.width + column] * self.intensity
+    }
+
+    // Texels are picked by their weight, then a point uniformly in them. The
+    // density over the picture becomes one over solid angle by dividing by
+    // the area the texels are stretched over, 2π² sin θ.
+    fn pdf_value(&self, direction: &Vec3) -> f64 {
+        let total = self.total_weight();
+        let (u, v) = self.direction_to_uv(direction);
+        let sin_theta = (PI * v).sin();
+        if total <= 0.0 || sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let (row, column) = self.texel_at(u, v);
+        let picture_density =
+            self.texel_weight(row, column) / total * (self.width * self.height) as f64;
+        picture_density / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn random(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let row = pick_from_sums(&self.row_sums, distribution.sample(rng));
+        let row_sums = &self.column_sums[row * self.width..(row + 1) * self.width];
+        let column = pick_from_sums(row_sums, distribution.sample(rng));
+        let u = (column as f64 + distribution.sample(rng)) / self.width as f64;
+        let v = (row as f64 + distribution.sample(rng)) / self.height as f64;
+        self.uv_to_direction(u, v)
+    }
+
+    // Each texel covers about 2π² sin θ / (width * height) steradians.
+    fn power(&self) -> f64 {
+        let texel_solid_angle = 2.0 * PI * PI / (self.width * self.height) as f64;
+        self.total_weight() * texel_solid_angle * self.intensity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    // A dim gradient with a bright sun in one texel.
+    fn sunny_map(rotation: f64) -> EnvironmentMap {
+        let (width, height) = (16, 8);
+        let mut texels: Vec<Vec3> = (0..width * height)
+            .map(|i| Vec3::new(0.1, 0.2, (i % width) as f64 / width as f64))
+            .collect();
+        texels[2 * width + 5] = Vec3::new(500.0, 400.0, 300.0);
+        EnvironmentMap::new(width, height, texels, rotation, 1.0)
+    }
+
+    #[test]
+    fn directions_round_trip() {
+        let map = sunny_map(0.7);
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.95), (0.3, 0.01)] {
+            let direction = map.uv_to_direction(u, v);
+            assert!((direction.norm() - 1.0).abs() < 1e-12);
+            let (found_u, found_v) = map.direction_to_uv(&direction);
+            assert!((found_u - u).abs() < 1e-9 && (found_v - v).abs() < 1e-9);
+        }
+        // Without rotation, the picture's center faces -z.
+        let center = sunny_map(0.0).uv_to_direction(0.5, 0.5);
+        assert!((center - Vec3::new(0.0, 0.0, -1.0)).norm() < 1e-12);
+    }
+
+    #[test]
+    fn environment_pdf_integrates_to_one() {
+        let map = sunny_map(0.3);
+        // Midpoint rule over the picture, with the area of the sphere each
+        // step covers.
+        let steps = 512;
+        let mut integral = 0.0;
+        for i in 0..steps {
+            for j in 0..steps {
+                let u = (i as f64 + 0.5) / steps as f64;
+                let v = (j as f64 + 0.5) / steps as f64;
+                let direction = map.uv_to_direction(u, v);
+                let solid_angle = 2.0 * PI * PI * (PI * v).sin() / (steps * steps) as f64;
+                integral += map.pdf_value(&direction) * solid_angle;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-6, "integral {}", integral);
+    }
+
+    #[test]
+    fn bright_texels_get_the_samples() {
+        let map = sunny_map(1.2);
+        let sun_weight = map.texel_weight(2, 5) / map.total_weight();
+        let mut rng = SmallRng::seed_from_u64(1);
+        let count = 20000;
+        let mut suns = 0;
+        for _ in 0..count {
+            let direction = map.random(&mut rng);
+            assert!(map.pdf_value(&direction) > 0.0);
+            let (u, v) = map.direction_to_uv(&direction);
+            suns += (map.texel_at(u, v) == (2, 5)) as usize;
+        }
+        assert!((suns as f64 / count as f64 - sun_weight).abs() < 0.01);
+    }
+
+    #[test]
+    fn uniform_maps_have_the_power_of_the_sphere() {
+        let map = EnvironmentMap::new(64, 32, vec![Vec3::new(1.0, 1.0, 1.0); 64 * 32], 0.0, 2.0);
+        assert!((map.power() - 2.0 * 4.0 * PI).abs() < 0.01 * 8.0 * PI);
+        // Uniform radiance is sampled uniformly.
+        let direction = Vec3::new(0.3, -0.4, 0.5);
+        assert!((map.pdf_value(&direction) - 1.0 / (4.0 * PI)).abs() < 0.01);
+    }
+}
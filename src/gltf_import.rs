@@ -0,0 +1,418 @@
+// glTF 2.0 import, for both `.gltf` (JSON with external or data URI buffers)
+// and `.glb` (binary) files.
+//
+// The node hierarchy of the default scene becomes nested Transforms around
+// TriangleMeshes, one mesh per primitive. Meshes referenced by several nodes
+// are only built once. The first perspective camera found gives the scene's
+// camera; its aspect ratio is ignored in favor of the output's.
+//
+// Punctual lights (KHR_lights_punctual), animations, skins, morph targets,
+// vertex colors and the normal, occlusion and metallic-roughness textures are
+// dropped.
+//
+// Metallic-roughness materials are mapped on the closest material this
+// renderer has:
+// - an emissive factor (scaled by KHR_materials_emissive_strength) gives a
+//   DiffuseLight, multiplying the emissive texture if present,
+// - KHR_materials_transmission or a blended, translucent base color gives a
+//   Dielectric with KHR_materials_ior's IOR,
+// - a metallic factor of 0.5 or more gives a Metal,
+// - everything else is Lambertian.
+// The base color texture is multiplied by the base color factor when present.
+use crate::bvh::FlatBvh;
+use crate::hittable::*;
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::{MeshBuffers, TriangleMesh};
+use crate::render::CameraSettings;
+use crate::texture::*;
+use base64::Engine;
+use gltf::mesh::Mode;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum GltfError {
+    Io(PathBuf, std::io::Error),
+    Invalid { path: PathBuf, message: String },
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfError::Io(path, err) => write!(f, "couldn't read {} ({})", path.display(), err),
+            GltfError::Invalid { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+pub struct GltfScene {
+    // None when the scene has no mesh.
+    pub world: Option<Arc<dyn Hittable>>,
+    pub camera: Option<CameraSettings>,
+}
+
+pub fn load_gltf(path: &Path) -> Result<GltfScene, GltfError> {
+    let bytes = std::fs::read(path).map_err(|err| GltfError::Io(path.to_path_buf(), err))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let invalid = |message: String| GltfError::Invalid {
+        path: path.to_path_buf(),
+        message,
+    };
+
+    let gltf::Gltf { document, mut blob } =
+        gltf::Gltf::from_slice(&bytes).map_err(|err| invalid(err.to_string()))?;
+    if document
+        .extensions_used()
+        .any(|extension| extension == "KHR_lights_punctual")
+    {
+        eprintln!(
+            "{}: punctual lights aren't supported, ignoring them",
+            path.display()
+        );
+    }
+
+    let mut buffers = vec![];
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob
+                .take()
+                .ok_or_else(|| invalid("missing binary chunk".to_string()))?,
+            gltf::buffer::Source::Uri(uri) => read_uri(uri, base_dir, path)?,
+        };
+        if data.len() < buffer.length() {
+            return Err(invalid(format!(
+                "buffer {} is {} bytes long instead of {}",
+                buffer.index(),
+                data.len(),
+                buffer.length()
+            )));
+        }
+        buffers.push(data);
+    }
+
+    let mut images = vec![];
+    for image in document.images() {
+        let decoded = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let start = view.offset();
+                let bytes = start
+                    .checked_add(view.length())
+                    .and_then(|end| buffers[view.buffer().index()].get(start..end));
+                match bytes {
+                    Some(bytes) => image::load_from_memory(bytes),
+                    None => {
+                        return Err(invalid(format!(
+                            "image {} is out of its buffer's bounds",
+                            image.index()
+                        )))
+                    }
+                }
+            }
+            gltf::image::Source::Uri { uri, .. } => {
+                image::load_from_memory(&read_uri(uri, base_dir, path)?)
+            }
+        };
+        match decoded {
+            Ok(decoded) => images.push(Arc::new(ImageTexture::from_image(&decoded))),
+            Err(err) => {
+                return Err(invalid(format!(
+                    "couldn't decode image {} ({})",
+                    image.index(),
+                    err
+                )))
+            }
+        }
+    }
+
+    let scene = match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => scene,
+        None => return Err(invalid("the file has no scene".to_string())),
+    };
+
+    let mut importer = Importer {
+        buffers,
+        images,
+        materials: HashMap::new(),
+        meshes: HashMap::new(),
+        camera: None,
+    };
+    let mut roots = vec![];
+    for node in scene.nodes() {
+        if let Some(object) = importer.visit_node(&node, &Mat4::identity())? {
+            roots.push(object);
+        }
+    }
+
+    Ok(GltfScene {
+        world: group(roots),
+        camera: importer.camera,
+    })
+}
+
+struct Importer {
+    buffers: Vec<Vec<u8>>,
+    images: Vec<Arc<ImageTexture>>,
+    // Keyed by material index, None being the glTF default material.
+    materials: HashMap<Option<usize>, Arc<dyn Material>>,
+    meshes: HashMap<usize, Option<Arc<dyn Hittable>>>,
+    camera: Option<CameraSettings>,
+}
+
+impl Importer {
+    // Returns the node's subtree in its parent's space.
+    fn visit_node(
+        &mut self,
+        node: &gltf::Node,
+        parent_to_world: &Mat4,
+    ) -> Result<Option<Arc<dyn Hittable>>, GltfError> {
+        let columns = node.transform().matrix();
+        let local = Mat4::from_fn(|row, column| columns[column][row] as f64);
+        // A null scale hides the whole subtree.
+        if local.try_inverse().is_none() {
+            return Ok(None);
+        }
+        let node_to_world = parent_to_world * local;
+
+        if let (None, Some(camera)) = (&self.camera, node.camera()) {
+            if let gltf::camera::Projection::Perspective(perspective) = camera.projection() {
+                // glTF cameras look down their -Z axis, +Y being up.
+                let eye = (node_to_world * Vec4::new(0.0, 0.0, 0.0, 1.0)).xyz();
+                let forward = (node_to_world * Vec4::new(0.0, 0.0, -1.0, 0.0)).xyz();
+                let mut settings = CameraSettings::looking_at(
+                    eye,
+                    eye + forward.normalize(),
+                    (perspective.yfov() as f64).to_degrees(),
+                );
+                settings.up = (node_to_world * Vec4::new(0.0, 1.0, 0.0, 0.0)).xyz();
+                self.camera = Some(settings);
+            }
+        }
+
+        let mut objects = vec![];
+        if let Some(mesh) = node.mesh() {
+            if let Some(object) = self.mesh(&mesh) {
+                objects.push(object);
+            }
+        }
+        for child in node.children() {
+            if let Some(object) = self.visit_node(&child, &node_to_world)? {
+                objects.push(object);
+            }
+        }
+
+        Ok(group(objects).map(|object| {
+            if local == Mat4::identity() {
+                object
+            } else {
+                Arc::new(Transform::new(&local, object)) as Arc<dyn Hittable>
+            }
+        }))
+    }
+
+    fn mesh(&mut self, mesh: &gltf::Mesh) -> Option<Arc<dyn Hittable>> {
+        if let Some(object) = self.meshes.get(&mesh.index()) {
+            return object.clone();
+        }
+
+        let mut primitives = vec![];
+        for primitive in mesh.primitives() {
+            let buffers = &self.buffers;
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()][..]));
+            let positions: Vec<Vec3> = match reader.read_positions() {
+                Some(positions) => positions.map(vec3_from).collect(),
+                None => continue,
+            };
+            let normals: Option<Vec<Vec3>> = reader
+                .read_normals()
+                .map(|normals| normals.map(vec3_from).collect());
+            // glTF's UV origin is the top left corner of the image.
+            let uvs: Option<Vec<Vec2>> = reader.read_tex_coords(0).map(|uvs| {
+                uvs.into_f32()
+                    .map(|[u, v]| Vec2::new(u as f64, 1.0 - v as f64))
+                    .collect()
+            });
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+
+            let triangles = match triangulate(primitive.mode(), &indices) {
+                Some(triangles) => triangles,
+                // Points and lines have no surface to render.
+                None => continue,
+            };
+            let vertex_count = positions.len();
+            let triangles: Vec<[u32; 3]> = triangles
+                .into_iter()
+                .filter(|t| t.iter().all(|&i| (i as usize) < vertex_count))
+                .filter(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0])
+                .collect();
+            if triangles.is_empty() {
+                continue;
+            }
+
+            let material = self.material(&primitive.material());
+            let buffers = Arc::new(MeshBuffers {
+                positions,
+                normals: normals.filter(|normals| normals.len() == vertex_count),
+                uvs: uvs.filter(|uvs| uvs.len() == vertex_count),
+                colors: None,
+            });
+            primitives.push(
+                Arc::new(TriangleMesh::new(buffers, triangles, material)) as Arc<dyn Hittable>
+            );
+        }
+
+        let object = group(primitives);
+        self.meshes.insert(mesh.index(), object.clone());
+        object
+    }
+
+    fn material(&mut self, material: &gltf::Material) -> Arc<dyn Material> {
+        if let Some(built) = self.materials.get(&material.index()) {
+            return built.clone();
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let base_color = self.texture(
+            pbr.base_color_texture(),
+            Vec3::new(r as f64, g as f64, b as f64),
+        );
+        let emissive = Vec3::from_iterator(material.emissive_factor().iter().map(|&c| c as f64))
+            * material.emissive_strength().unwrap_or(1.0) as f64;
+        let transmission = material
+            .transmission()
+            .map_or(0.0, |transmission| transmission.transmission_factor());
+        let translucent = material.alpha_mode() == gltf::material::AlphaMode::Blend && alpha < 1.0;
+
+        let built: Arc<dyn Material> = if emissive.max() > 0.0 {
+            Arc::new(DiffuseLight {
+                emissive: self.texture(material.emissive_texture(), emissive),
+            })
+        } else if transmission > 0.0 || translucent {
+            Arc::new(Dielectric {
+                ior: material.ior().unwrap_or(1.5) as f64,
+            })
+        } else if pbr.metallic_factor() >= 0.5 {
+            Arc::new(Metal {
+                albedo: base_color,
+                roughness: pbr.roughness_factor() as f64,
+            })
+        } else {
+            Arc::new(Lambertian { albedo: base_color })
+        };
+        self.materials.insert(material.index(), built.clone());
+        built
+    }
+
+    fn texture(&self, info: Option<gltf::texture::Info>, factor: Vec3) -> Arc<dyn Texture> {
+        match info {
+            Some(info) if factor == Vec3::from_element(1.0) => {
+                self.images[info.texture().source().index()].clone()
+            }
+            Some(info) => Arc::new(ScaledTexture {
+                texture: self.images[info.texture().source().index()].clone(),
+                scale: factor,
+            }),
+            None => Arc::new(SolidColor { albedo: factor }),
+        }
+    }
+}
+
+fn vec3_from(v: [f32; 3]) -> Vec3 {
+    Vec3::new(v[0] as f64, v[1] as f64, v[2] as f64)
+}
+
+fn triangulate(mode: Mode, indices: &[u32]) -> Option<Vec<[u32; 3]>> {
+    let triangles = match mode {
+        Mode::Triangles => indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect(),
+        // Every other strip triangle is flipped to keep a consistent winding.
+        Mode::TriangleStrip => indices
+            .windows(3)
+            .enumerate()
+            .map(|(i, t)| {
+                if i % 2 == 0 {
+                    [t[0], t[1], t[2]]
+                } else {
+                    [t[1], t[0], t[2]]
+                }
+            })
+            .collect(),
+        Mode::TriangleFan => indices
+            .windows(2)
+            .skip(1)
+            .map(|t| [indices[0], t[0], t[1]])
+            .collect(),
+        _ => return None,
+    };
+    Some(triangles)
+}
+
+fn group(mut objects: Vec<Arc<dyn Hittable>>) -> Option<Arc<dyn Hittable>> {
+    match objects.len() {
+        0 => None,
+        1 => objects.pop(),
+        _ => Some(Arc::new(FlatBvh::new(&objects, 0.0, f64::INFINITY))),
+    }
+}
+
+// Buffers and images are either embedded as base64 data URIs or stored next
+// to the glTF file.
+fn read_uri(uri: &str, base_dir: &Path, gltf_path: &Path) -> Result<Vec<u8>, GltfError> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let encoded = match data.split_once(";base64,") {
+            Some((_, encoded)) => encoded,
+            None => {
+                return Err(GltfError::Invalid {
+                    path: gltf_path.to_path_buf(),
+                    message: "only base64 data URIs are supported".to_string(),
+                })
+            }
+        };
+        return base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|err| GltfError::Invalid {
+                path: gltf_path.to_path_buf(),
+                message: format!("invalid data URI ({})", err),
+            });
+    }
+
+    let path = base_dir.join(percent_decode(uri));
+    std::fs::read(&path).map_err(|err| GltfError::Io(path, err))
+}
+
+// Relative URIs may escape spaces and other reserved characters.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -1,16 +1,81 @@
-use crate::material::Material;
+use crate::bvh::sah_partition;
+use crate::colors::luminance;
+use crate::material::{Isotropic, Material};
 use crate::math::*;
+use crate::texture::Texture;
 use crate::{HitRecord, Ray};
-use nalgebra::Unit;
+use nalgebra::U3;
 use rand::RngCore;
-use rand_distr::{Distribution, Uniform};
+use rand_distr::{Distribution, Uniform, UnitSphere};
 use std::sync::Arc;
 
 use std::f64::consts::{PI, TAU};
 
 pub trait Hittable: Sync + Send {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
     fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB>;
+
+    // Lights are the emissive hittables that can be sampled: random gives a
+    // direction from origin towards one of their points and pdf_value the
+    // solid angle density of picking a given direction.
+    fn is_light(&self) -> bool {
+        false
+    }
+
+    fn pdf_value(&self, _origin: &Vec3, _direction: &Vec3) -> f64 {
+        0.0
+    }
+
+    fn random(&self, _origin: &Vec3, _rng: &mut dyn RngCore) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+
+    // How bright a light is overall, its emitted luminance times its area, to
+    // pick the brighter lights more often.
+    fn power(&self) -> f64 {
+        0.0
+    }
+
+    // The lights a container holds, even deep inside, so they can be sampled
+    // on their own.
+    fn lights(&self) -> Vec<Arc<dyn Hittable>> {
+        vec![]
+    }
+}
+
+pub(crate) fn collect_lights(objects: &[Arc<dyn Hittable>]) -> Vec<Arc<dyn Hittable>> {
+    let mut lights = vec![];
+    for object in objects {
+        if object.is_light() {
+            lights.push(Arc::clone(object));
+        } else {
+            lights.extend(object.lights());
+        }
+    }
+    lights
+}
+
+// Luminance emitted at a point, as an estimate for a whole light.
+pub(crate) fn emitted_luminance(material: &dyn Material, u: f64, v: f64, p: &Vec3) -> f64 {
+    luminance(&material.emitted(u, v, p)).max(0.0)
+}
+
+// Solid angle density of the directions from origin towards uniformly picked
+// points of a surface, found by casting a ray in the given direction.
+fn area_pdf_value(hittable: &dyn Hittable, area: f64, origin: &Vec3, direction: &Vec3) -> f64 {
+    let ray = Ray {
+        origin: *origin,
+        direction: *direction,
+        time: 0.0,
+    };
+    match hittable.hit(&ray, 0.001, f64::INFINITY) {
+        Some(record) => {
+            let distance_squared = record.t * record.t * direction.norm_squared();
+            let cosine = (direction.dot(&record.normal) / direction.norm()).abs();
+            distance_squared / (cosine * area)
+        }
+        None => 0.0,
+    }
 }
 
 // - Transform -
@@ -18,8 +83,11 @@ pub trait Hittable: Sync + Send {
 pub struct Transform {
     transform: Mat4,
     inv_transform: Mat4,
+    // Directions only go through the linear part, and normals through its
+    // inverse transpose to stay perpendicular under non-uniform scales.
+    linear: Mat4,
+    inv_linear: Mat4,
     normal_mat: Mat4,
-    inv_normal: Mat4,
     child: Arc<dyn Hittable>,
     bbox: Option<AABB>,
 }
@@ -27,17 +95,20 @@ pub struct Transform {
 impl Transform {
     pub fn new(transform: &Mat4, child: Arc<dyn Hittable>) -> Transform {
         let bbox = Transform::get_transformed_bbox(0., 1., transform, &child);
-        let mut rot_only = transform.clone();
+        let mut rot_only = *transform;
         rot_only[(0, 3)] = 0.;
         rot_only[(1, 3)] = 0.;
         rot_only[(2, 3)] = 0.;
         rot_only[(3, 3)] = 1.;
 
+        let inv_rot_only = rot_only.try_inverse().unwrap();
+
         Transform {
-            transform: transform.clone(),
+            transform: *transform,
             inv_transform: transform.try_inverse().unwrap(),
-            normal_mat: rot_only,
-            inv_normal: rot_only.try_inverse().unwrap(),
+            linear: rot_only,
+            inv_linear: inv_rot_only,
+            normal_mat: inv_rot_only.transpose(),
             child,
             bbox,
         }
@@ -49,12 +120,7 @@ impl Transform {
         transform: &Mat4,
         child: &Arc<dyn Hittable>,
     ) -> Option<AABB> {
-        let bbox = child.bounding_box(t0, t1);
-        if let None = bbox {
-            return None;
-        }
-
-        let bbox = bbox.unwrap();
+        let bbox = child.bounding_box(t0, t1)?;
 
         let mut vmin = Vec3::from_element(f64::INFINITY);
         let mut vmax = Vec3::from_element(f64::NEG_INFINITY);
@@ -79,30 +145,15 @@ impl Transform {
 
         Some(AABB::new(vmin, vmax))
     }
-
-    pub fn from_rot_x(angle: f64, child: Arc<dyn Hittable>) -> Transform {
-        let axis = Unit::new_normalize(Vec3::new(1., 0., 0.));
-        Transform::new(&Mat4::from_axis_angle(&axis, angle), child)
-    }
-
-    pub fn from_rot_y(angle: f64, child: Arc<dyn Hittable>) -> Transform {
-        let axis = Unit::new_normalize(Vec3::new(0., 1., 0.));
-        Transform::new(&Mat4::from_axis_angle(&axis, angle), child)
-    }
-
-    pub fn from_rot_z(angle: f64, child: Arc<dyn Hittable>) -> Transform {
-        let axis = Unit::new_normalize(Vec3::new(0., 0., 1.));
-        Transform::new(&Mat4::from_axis_angle(&axis, angle), child)
-    }
 }
 
 impl Hittable for Transform {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let origin = Vec4::new(ray.origin.x, ray.origin.y, ray.origin.z, 1.);
         let dir = Vec4::new(ray.direction.x, ray.direction.y, ray.direction.z, 1.);
 
         let inverse_origin = self.inv_transform * origin;
-        let inverse_dir = self.inv_normal * dir;
+        let inverse_dir = self.inv_linear * dir;
 
         let record = self.child.hit(
             &Ray {
@@ -112,12 +163,7 @@ impl Hittable for Transform {
             },
             t_min,
             t_max,
-        );
-        if record.is_none() {
-            return None;
-        }
-
-        let record = record.unwrap();
+        )?;
         let p = Vec4::new(record.p.x, record.p.y, record.p.z, 1.);
         let p = self.transform * p;
         let normal = Vec4::new(record.normal.x, record.normal.y, record.normal.z, 1.);
@@ -132,12 +178,45 @@ impl Hittable for Transform {
             p: p.xyz(),
             u: record.u,
             v: record.v,
+            vertex_color: record.vertex_color,
         })
     }
 
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         self.bbox.clone()
     }
+
+    // Densities are kept as they are, which only holds for rotations and
+    // uniform scales as they preserve solid angles.
+    fn is_light(&self) -> bool {
+        self.child.is_light()
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        let origin = self.inv_transform * Vec4::new(origin.x, origin.y, origin.z, 1.);
+        let direction = self.inv_linear * Vec4::new(direction.x, direction.y, direction.z, 1.);
+        self.child.pdf_value(&origin.xyz(), &direction.xyz())
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let origin = self.inv_transform * Vec4::new(origin.x, origin.y, origin.z, 1.);
+        let direction = self.child.random(&origin.xyz(), rng);
+        (self.linear * Vec4::new(direction.x, direction.y, direction.z, 1.)).xyz()
+    }
+
+    // Areas grow with the square of the scale.
+    fn power(&self) -> f64 {
+        let determinant = self.transform.fixed_slice::<U3, U3>(0, 0).determinant();
+        self.child.power() * determinant.abs().powf(2.0 / 3.0)
+    }
+
+    fn lights(&self) -> Vec<Arc<dyn Hittable>> {
+        self.child
+            .lights()
+            .into_iter()
+            .map(|light| Arc::new(Transform::new(&self.transform, light)) as Arc<dyn Hittable>)
+            .collect()
+    }
 }
 
 // - Sphere -
@@ -158,8 +237,8 @@ impl Sphere {
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        match ray_sphere_intersection(&self.center, self.radius, &ray, t_min, t_max) {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        match ray_sphere_intersection(&self.center, self.radius, ray, t_min, t_max) {
             Some((root, point, normal)) => {
                 let (u, v) = Sphere::get_uv(&normal);
                 Some(HitRecord::from_uv(
@@ -183,6 +262,57 @@ impl Hittable for Sphere {
             self.center + radius_vector,
         ))
     }
+
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    // From the outside, directions are picked uniformly in the cone the
+    // sphere covers. From the inside, towards uniformly picked points.
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        let distance_squared = (self.center - origin).norm_squared();
+        if distance_squared <= self.radius * self.radius {
+            return area_pdf_value(
+                self,
+                4.0 * PI * self.radius * self.radius,
+                origin,
+                direction,
+            );
+        }
+        let ray = Ray {
+            origin: *origin,
+            direction: *direction,
+            time: 0.0,
+        };
+        if self.hit(&ray, 0.001, f64::INFINITY).is_none() {
+            return 0.0;
+        }
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        1.0 / (TAU * (1.0 - cos_theta_max))
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let to_center = self.center - origin;
+        let distance_squared = to_center.norm_squared();
+        if distance_squared <= self.radius * self.radius {
+            let v: [f64; 3] = UnitSphere.sample(rng);
+            return self.center + Vec3::new(v[0], v[1], v[2]) * self.radius - origin;
+        }
+        let distribution = Uniform::from(0.0..1.0);
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        let cos_theta = 1.0 + distribution.sample(rng) * (cos_theta_max - 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = TAU * distribution.sample(rng);
+
+        let axis = to_center / distance_squared.sqrt();
+        let (tangent, bitangent) = orthonormal_basis(&axis);
+        axis * cos_theta + tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin())
+    }
+
+    fn power(&self) -> f64 {
+        let area = 4.0 * PI * self.radius * self.radius;
+        area * emitted_luminance(self.material.as_ref(), 0.5, 0.5, &self.center)
+    }
 }
 
 pub struct MovingSphere {
@@ -202,18 +332,13 @@ impl MovingSphere {
 }
 
 impl Hittable for MovingSphere {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let center_at_time = self.center_at(ray.time);
-        match ray_sphere_intersection(&center_at_time, self.radius, &ray, t_min, t_max) {
-            Some((root, point, normal)) => Some(HitRecord::from(
-                root,
-                point,
-                ray.direction,
-                normal,
-                self.material.as_ref(),
-            )),
-            None => None,
-        }
+        ray_sphere_intersection(&center_at_time, self.radius, ray, t_min, t_max).map(
+            |(root, point, normal)| {
+                HitRecord::from(root, point, ray.direction, normal, self.material.as_ref())
+            },
+        )
     }
 
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
@@ -230,7 +355,7 @@ impl Hittable for MovingSphere {
     }
 }
 
-fn ray_sphere_intersection(
+pub(crate) fn ray_sphere_intersection(
     center: &Vec3,
     radius: f64,
     ray: &Ray,
@@ -270,7 +395,7 @@ pub struct XyPlane {
 }
 
 impl Hittable for XyPlane {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let t = (self.k - ray.origin.z) / ray.direction.z;
 
         if t < t_min || t > t_max {
@@ -304,6 +429,29 @@ impl Hittable for XyPlane {
             max: Vec3::new(self.max.x, self.max.y, self.k + 1e-4),
         })
     }
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf_value(self, area, origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let a = self.min.x + distribution.sample(rng) * (self.max.x - self.min.x);
+        let b = self.min.y + distribution.sample(rng) * (self.max.y - self.min.y);
+        Vec3::new(a, b, self.k) - origin
+    }
+
+    fn power(&self) -> f64 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        let a = (self.min.x + self.max.x) / 2.0;
+        let b = (self.min.y + self.max.y) / 2.0;
+        let center = Vec3::new(a, b, self.k);
+        area * emitted_luminance(self.material.as_ref(), 0.5, 0.5, &center)
+    }
 }
 
 pub struct XzPlane {
@@ -314,7 +462,7 @@ pub struct XzPlane {
 }
 
 impl Hittable for XzPlane {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let t = (self.k - ray.origin.y) / ray.direction.y;
 
         if t < t_min || t > t_max {
@@ -348,6 +496,29 @@ impl Hittable for XzPlane {
             max: Vec3::new(self.max.x, self.k + 1e-4, self.max.y),
         })
     }
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf_value(self, area, origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let a = self.min.x + distribution.sample(rng) * (self.max.x - self.min.x);
+        let b = self.min.y + distribution.sample(rng) * (self.max.y - self.min.y);
+        Vec3::new(a, self.k, b) - origin
+    }
+
+    fn power(&self) -> f64 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        let a = (self.min.x + self.max.x) / 2.0;
+        let b = (self.min.y + self.max.y) / 2.0;
+        let center = Vec3::new(a, self.k, b);
+        area * emitted_luminance(self.material.as_ref(), 0.5, 0.5, &center)
+    }
 }
 
 pub struct YzPlane {
@@ -358,7 +529,7 @@ pub struct YzPlane {
 }
 
 impl Hittable for YzPlane {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let t = (self.k - ray.origin.x) / ray.direction.x;
 
         if t < t_min || t > t_max {
@@ -392,6 +563,217 @@ impl Hittable for YzPlane {
             max: Vec3::new(self.k + 1e-4, self.max.x, self.max.y),
         })
     }
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf_value(self, area, origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let a = self.min.x + distribution.sample(rng) * (self.max.x - self.min.x);
+        let b = self.min.y + distribution.sample(rng) * (self.max.y - self.min.y);
+        Vec3::new(self.k, a, b) - origin
+    }
+
+    fn power(&self) -> f64 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        let a = (self.min.x + self.max.x) / 2.0;
+        let b = (self.min.y + self.max.y) / 2.0;
+        let center = Vec3::new(self.k, a, b);
+        area * emitted_luminance(self.material.as_ref(), 0.5, 0.5, &center)
+    }
+}
+
+// - Triangle -
+
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    // Per-vertex shading normals, the face normal is used when missing.
+    pub normals: Option<[Vec3; 3]>,
+    // Per-vertex UVs, (0, 0), (1, 0), (0, 1) when missing.
+    pub uvs: Option<[Vec2; 3]>,
+    pub material: Arc<dyn Material>,
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let [a, b, c] = &self.vertices;
+        let (t, barycentrics) = ray_triangle_intersection(a, b, c, ray, t_min, t_max)?;
+        Some(triangle_hit_record(
+            t,
+            ray,
+            &self.vertices,
+            self.normals.as_ref(),
+            self.uvs.as_ref(),
+            &barycentrics,
+            self.material.as_ref(),
+        ))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        Some(triangle_bounding_box(&self.vertices))
+    }
+
+    fn is_light(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        area_pdf_value(self, triangle_area(&self.vertices), origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        triangle_random_point(&self.vertices, rng) - origin
+    }
+
+    fn power(&self) -> f64 {
+        let center = (self.vertices[0] + self.vertices[1] + self.vertices[2]) / 3.0;
+        let area = triangle_area(&self.vertices);
+        area * emitted_luminance(self.material.as_ref(), 0.5, 0.5, &center)
+    }
+}
+
+pub(crate) fn triangle_area(vertices: &[Vec3; 3]) -> f64 {
+    (vertices[1] - vertices[0])
+        .cross(&(vertices[2] - vertices[0]))
+        .norm()
+        / 2.0
+}
+
+// Uniformly picked point of a triangle, folding the unit square's upper half
+// back onto the lower one.
+pub(crate) fn triangle_random_point(vertices: &[Vec3; 3], rng: &mut dyn RngCore) -> Vec3 {
+    let distribution = Uniform::from(0.0..1.0);
+    let mut a: f64 = distribution.sample(rng);
+    let mut b: f64 = distribution.sample(rng);
+    if a + b > 1.0 {
+        a = 1.0 - a;
+        b = 1.0 - b;
+    }
+    vertices[0] + (vertices[1] - vertices[0]) * a + (vertices[2] - vertices[0]) * b
+}
+
+pub(crate) fn triangle_bounding_box(vertices: &[Vec3; 3]) -> AABB {
+    let min = vmin(&vmin(&vertices[0], &vertices[1]), &vertices[2]);
+    let max = vmax(&vmax(&vertices[0], &vertices[1]), &vertices[2]);
+    // Same padding as the planes so axis-aligned triangles still have a volume.
+    let padding = Vec3::from_element(1e-4);
+    AABB::new(min - padding, max + padding)
+}
+
+// Fills a hit record from the barycentric coordinates of the hit, shared with
+// the meshes.
+pub(crate) fn triangle_hit_record<'a>(
+    t: f64,
+    ray: &Ray,
+    vertices: &[Vec3; 3],
+    normals: Option<&[Vec3; 3]>,
+    uvs: Option<&[Vec2; 3]>,
+    barycentrics: &Vec3,
+    material: &'a dyn Material,
+) -> HitRecord<'a> {
+    let geometric_normal = (vertices[1] - vertices[0])
+        .cross(&(vertices[2] - vertices[0]))
+        .normalize();
+    let (front_facing, _) = HitRecord::set_face_normal(ray.direction, geometric_normal);
+
+    let normal = match normals {
+        Some(n) => {
+            let interpolated =
+                n[0] * barycentrics.x + n[1] * barycentrics.y + n[2] * barycentrics.z;
+            // Degenerate interpolated normals fall back on the face one.
+            if interpolated.norm_squared() > 0.0 {
+                interpolated.normalize()
+            } else {
+                geometric_normal
+            }
+        }
+        None => geometric_normal,
+    };
+    // Like the other hittables, the normal faces the incoming ray.
+    let normal = if ray.direction.dot(&normal) < 0.0 {
+        normal
+    } else {
+        -normal
+    };
+
+    let uv = match uvs {
+        Some(uv) => uv[0] * barycentrics.x + uv[1] * barycentrics.y + uv[2] * barycentrics.z,
+        None => Vec2::new(barycentrics.y, barycentrics.z),
+    };
+
+    HitRecord {
+        t,
+        p: ray.at(t),
+        normal,
+        front_facing,
+        material,
+        u: uv.x,
+        v: uv.y,
+        vertex_color: None,
+    }
+}
+
+// Watertight ray/triangle intersection (Woop, Benthin and Wald, 2013), no
+// hole or double hit along shared edges. Returns the distance and the
+// barycentric weights of a, b and c.
+pub(crate) fn ray_triangle_intersection(
+    a: &Vec3,
+    b: &Vec3,
+    c: &Vec3,
+    ray: &Ray,
+    t_min: f64,
+    t_max: f64,
+) -> Option<(f64, Vec3)> {
+    let dir = &ray.direction;
+    // Permute the axes so the ray mostly goes along z.
+    let kz = dir.iamax();
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear the triangle into ray space.
+    let sx = dir[kx] / dir[kz];
+    let sy = dir[ky] / dir[kz];
+    let sz = dir[kz].recip();
+
+    let a = a - ray.origin;
+    let b = b - ray.origin;
+    let c = c - ray.origin;
+
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentrics, they all have to share the same sign.
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let scaled_t = u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz];
+    let t = scaled_t / det;
+    if t < t_min || t > t_max {
+        return None;
+    }
+
+    Some((t, Vec3::new(u, v, w) / det))
 }
 
 // - Box -
@@ -404,46 +786,44 @@ pub struct Cube {
 
 impl Cube {
     pub fn new(min: Vec3, max: Vec3, material: Arc<dyn Material>, rng: &mut impl RngCore) -> Cube {
-        let mut side_vec: Vec<Arc<dyn Hittable>> = vec![];
-
-        side_vec.push(Arc::new(XyPlane {
-            min: min.xy(),
-            max: max.xy(),
-            k: min.z,
-            material: material.clone(),
-        }));
-        side_vec.push(Arc::new(XyPlane {
-            min: min.xy(),
-            max: max.xy(),
-            k: max.z,
-            material: material.clone(),
-        }));
-
-        side_vec.push(Arc::new(XzPlane {
-            min: min.xz(),
-            max: max.xz(),
-            k: min.y,
-            material: material.clone(),
-        }));
-        side_vec.push(Arc::new(XzPlane {
-            min: min.xz(),
-            max: max.xz(),
-            k: max.y,
-            material: material.clone(),
-        }));
-
-        side_vec.push(Arc::new(YzPlane {
-            min: min.yz(),
-            max: max.yz(),
-            k: min.x,
-            material: material.clone(),
-        }));
-        side_vec.push(Arc::new(YzPlane {
-            min: min.yz(),
-            max: max.yz(),
-            k: max.x,
-            material: material.clone(),
-        }));
+        let side_vec: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(XyPlane {
+                min: min.xy(),
+                max: max.xy(),
+                k: min.z,
+                material: material.clone(),
+            }),
+            Arc::new(XyPlane {
+                min: min.xy(),
+                max: max.xy(),
+                k: max.z,
+                material: material.clone(),
+            }),
+            Arc::new(XzPlane {
+                min: min.xz(),
+                max: max.xz(),
+                k: min.y,
+                material: material.clone(),
+            }),
+            Arc::new(XzPlane {
+                min: min.xz(),
+                max: max.xz(),
+                k: max.y,
+                material: material.clone(),
+            }),
+            Arc::new(YzPlane {
+                min: min.yz(),
+                max: max.yz(),
+                k: min.x,
+                material: material.clone(),
+            }),
+            Arc::new(YzPlane {
+                min: min.yz(),
+                max: max.yz(),
+                k: max.x,
+                material: material.clone(),
+            }),
+        ];
 
         Cube {
             sides: HittableList::from_slice(&side_vec, 0., 1., rng),
@@ -453,13 +833,170 @@ impl Cube {
 }
 
 impl Hittable for Cube {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         self.sides.hit(ray, t_min, t_max)
     }
 
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Some(self.bbox.clone())
     }
+
+    fn lights(&self) -> Vec<Arc<dyn Hittable>> {
+        self.sides.lights()
+    }
+}
+
+// - Volumes -
+
+// A volume of constant density filling a closed boundary (only convex ones,
+// a ray enters and leaves it once). Rays travel a random distance inside, with
+// an exponential distribution, before scattering on the phase function.
+pub struct ConstantMedium {
+    pub boundary: Arc<dyn Hittable>,
+    pub neg_inv_density: f64,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl ConstantMedium {
+    pub fn new(
+        boundary: Arc<dyn Hittable>,
+        density: f64,
+        albedo: Arc<dyn Texture>,
+        emission: Option<Arc<dyn Texture>>,
+    ) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase_function: Arc::new(Isotropic { albedo, emission }),
+        }
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let entry = self.boundary.hit(ray, f64::NEG_INFINITY, f64::INFINITY)?;
+        let exit = self.boundary.hit(ray, entry.t + 0.0001, f64::INFINITY)?;
+
+        let t_entry = entry.t.max(t_min).max(0.0);
+        let t_exit = exit.t.min(t_max);
+        if t_entry >= t_exit {
+            return None;
+        }
+
+        let ray_length = ray.direction.norm();
+        let distance_inside = (t_exit - t_entry) * ray_length;
+        let random: f64 = Uniform::from(0.0..1.0).sample(&mut ray.rng_at(t_entry));
+        let hit_distance = self.neg_inv_density * random.ln();
+        if hit_distance > distance_inside {
+            return None;
+        }
+
+        let t = t_entry + hit_distance / ray_length;
+        // The normal and facing are arbitrary, the phase function ignores them.
+        Some(HitRecord {
+            t,
+            p: ray.at(t),
+            normal: Vec3::new(1.0, 0.0, 0.0),
+            front_facing: true,
+            material: self.phase_function.as_ref(),
+            u: 0.0,
+            v: 0.0,
+            vertex_color: None,
+        })
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        self.boundary.bounding_box(t0, t1)
+    }
+}
+
+// A volume whose density varies in space, following a texture (a turbulence
+// noise for clouds or smoke) looked up at `p * scale + offset`. The texture's
+// values are expected in [0, 1] and clamped there, `density` being the
+// density where they're 1.
+//
+// Free-flight distances are sampled with delta tracking (Woodcock tracking):
+// tentative collisions are drawn against the constant majorant `density` and
+// accepted with a probability of the local density over the majorant. The
+// rejected ones are null collisions, so the estimate is unbiased whatever the
+// field looks like.
+pub struct HeterogeneousMedium {
+    pub boundary: Arc<dyn Hittable>,
+    pub field: Arc<dyn Texture>,
+    pub density: f64,
+    pub scale: f64,
+    pub offset: Vec3,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl HeterogeneousMedium {
+    fn density_ratio(&self, p: &Vec3) -> f64 {
+        let value = self.field.value(0.0, 0.0, &(p * self.scale + self.offset));
+        (value.sum() / 3.0).clamp(0.0, 1.0)
+    }
+}
+
+impl Hittable for HeterogeneousMedium {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let entry = self.boundary.hit(ray, f64::NEG_INFINITY, f64::INFINITY)?;
+        let exit = self.boundary.hit(ray, entry.t + 0.0001, f64::INFINITY)?;
+
+        let t_entry = entry.t.max(t_min).max(0.0);
+        let t_exit = exit.t.min(t_max);
+        if t_entry >= t_exit {
+            return None;
+        }
+
+        delta_tracking(
+            ray,
+            t_entry,
+            t_exit,
+            self.density,
+            |p| self.density_ratio(p),
+            self.phase_function.as_ref(),
+        )
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        self.boundary.bounding_box(t0, t1)
+    }
+}
+
+// Samples a collision between t_entry and t_exit, tentative ones being drawn
+// against the majorant and kept with a probability of density_ratio.
+pub(crate) fn delta_tracking<'a>(
+    ray: &Ray,
+    t_entry: f64,
+    t_exit: f64,
+    majorant: f64,
+    density_ratio: impl Fn(&Vec3) -> f64,
+    phase_function: &'a dyn Material,
+) -> Option<HitRecord<'a>> {
+    let mut rng = ray.rng_at(t_entry);
+    let distribution = Uniform::from(0.0..1.0);
+    // Distances are in ray parameter units.
+    let majorant = majorant * ray.direction.norm();
+    let mut t = t_entry;
+    loop {
+        let random: f64 = distribution.sample(&mut rng);
+        t -= (1.0 - random).ln() / majorant;
+        if t >= t_exit {
+            return None;
+        }
+        let p = ray.at(t);
+        if distribution.sample(&mut rng) < density_ratio(&p) {
+            return Some(HitRecord {
+                t,
+                p,
+                normal: Vec3::new(1.0, 0.0, 0.0),
+                front_facing: true,
+                material: phase_function,
+                u: 0.0,
+                v: 0.0,
+                vertex_color: None,
+            });
+        }
+    }
 }
 
 // - Container structures -
@@ -501,15 +1038,12 @@ impl BvhNode {
             }
             _ => {
                 for hittable in data {
-                    let hittable = Arc::clone(&hittable);
-                    copy.push(hittable);
+                    copy.push(Arc::clone(hittable));
                 }
+                // One axis for the whole node, the sort needs a consistent order.
+                let axis = Uniform::from(0..3).sample(rng);
                 copy.sort_by(|left, right| {
-                    BvhNode::box_compare(
-                        left.as_ref(),
-                        right.as_ref(),
-                        Uniform::from(0..3).sample(rng),
-                    )
+                    BvhNode::box_compare(left.as_ref(), right.as_ref(), axis)
                 });
                 let mid = span / 2;
                 let (left, right) = copy.split_at(mid);
@@ -527,6 +1061,49 @@ impl BvhNode {
         }
     }
 
+    // Same tree built with a binned SAH instead of random median splits. The
+    // bounding boxes are only computed once.
+    pub fn from_slice_sah(data: &[Arc<dyn Hittable>], t0: f64, t1: f64) -> BvhNode {
+        let mut items: Vec<(AABB, Arc<dyn Hittable>)> = data
+            .iter()
+            .map(|hittable| {
+                let bbox = hittable.bounding_box(t0, t1).unwrap_or_else(|| {
+                    eprintln!("No bbox in BvhNode constructor");
+                    AABB::zeros()
+                });
+                (bbox, hittable.clone())
+            })
+            .collect();
+        BvhNode::build_sah(&mut items[..])
+    }
+
+    fn build_sah(items: &mut [(AABB, Arc<dyn Hittable>)]) -> BvhNode {
+        match items {
+            [(bbox, hittable)] => BvhNode {
+                left: hittable.clone(),
+                right: hittable.clone(),
+                node_box: bbox.clone(),
+            },
+            [(left_box, left), (right_box, right)] => BvhNode {
+                left: left.clone(),
+                right: right.clone(),
+                node_box: left_box.union(right_box),
+            },
+            _ => {
+                let split = sah_partition(items, |(bbox, _)| bbox);
+                let (left, right) = items.split_at_mut(split.mid);
+                let left = BvhNode::build_sah(left);
+                let right = BvhNode::build_sah(right);
+                let node_box = left.node_box.union(&right.node_box);
+                BvhNode {
+                    left: Arc::new(left),
+                    right: Arc::new(right),
+                    node_box,
+                }
+            }
+        }
+    }
+
     fn box_compare(a: &dyn Hittable, b: &dyn Hittable, axis: u8) -> std::cmp::Ordering {
         let box_a = a.bounding_box(0.0, 0.0);
         let box_b = b.bounding_box(0.0, 0.0);
@@ -546,23 +1123,32 @@ impl BvhNode {
 }
 
 impl Hittable for BvhNode {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        if !self.node_box.intersects(&ray, t_min, t_max) {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        if !self.node_box.intersects(ray, t_min, t_max) {
             return None;
         }
-        if let Some(hit_left) = self.left.as_ref().hit(&ray, t_min, t_max) {
+        if let Some(hit_left) = self.left.as_ref().hit(ray, t_min, t_max) {
             let t_max: f64 = hit_left.t;
-            if let Some(hit_right) = self.right.as_ref().hit(&ray, t_min, t_max) {
+            if let Some(hit_right) = self.right.as_ref().hit(ray, t_min, t_max) {
                 return Some(hit_right);
             }
             return Some(hit_left);
         }
-        return self.right.as_ref().hit(&ray, t_min, t_max);
+        self.right.as_ref().hit(ray, t_min, t_max)
     }
 
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Some(self.node_box.clone())
     }
+
+    // Single children are stored on both sides.
+    fn lights(&self) -> Vec<Arc<dyn Hittable>> {
+        if Arc::ptr_eq(&self.left, &self.right) {
+            collect_lights(std::slice::from_ref(&self.left))
+        } else {
+            collect_lights(&[self.left.clone(), self.right.clone()])
+        }
+    }
 }
 
 pub struct HittableList {
@@ -592,7 +1178,7 @@ impl HittableList {
 }
 
 impl Hittable for HittableList {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let mut closest_record: Option<HitRecord> = None;
         for hittable in &self.hittables {
             if let Some(record) = hittable.as_ref().hit(ray, t_min, t_max) {
@@ -607,4 +1193,160 @@ impl Hittable for HittableList {
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         Some(self.list_boundaries.clone())
     }
+
+    fn lights(&self) -> Vec<Arc<dyn Hittable>> {
+        collect_lights(&self.hittables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::SolidColor;
+
+    fn triangle(vertices: [Vec3; 3]) -> Triangle {
+        Triangle {
+            vertices,
+            normals: None,
+            uvs: None,
+            material: Arc::new(Lambertian {
+                albedo: Arc::new(SolidColor {
+                    albedo: Vec3::new(0.5, 0.5, 0.5),
+                }),
+            }),
+        }
+    }
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time: 0.0,
+        }
+    }
+
+    #[test]
+    fn rays_hit_triangles() {
+        let triangle = triangle([
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ]);
+        let down = ray(Vec3::new(0.25, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0));
+        let record = triangle.hit(&down, 0.001, f64::INFINITY).unwrap();
+        assert!((record.t - 2.0).abs() < 1e-9);
+        assert!((record.p - Vec3::new(0.25, 0.5, 0.0)).norm() < 1e-9);
+        // Without UVs, the barycentric weights of the second and third vertex.
+        assert!((record.u - 0.25).abs() < 1e-9 && (record.v - 0.5).abs() < 1e-9);
+        assert!((record.normal - Vec3::new(0.0, 0.0, 1.0)).norm() < 1e-9);
+        assert!(record.front_facing);
+
+        // From behind, the normal still faces the ray.
+        let up = ray(Vec3::new(0.25, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        let record = triangle.hit(&up, 0.001, f64::INFINITY).unwrap();
+        assert!((record.normal - Vec3::new(0.0, 0.0, -1.0)).norm() < 1e-9);
+        assert!(!record.front_facing);
+    }
+
+    #[test]
+    fn rays_miss_triangles() {
+        let triangle = triangle([
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ]);
+        let down = Vec3::new(0.0, 0.0, -1.0);
+        // Past the hypotenuse.
+        assert!(triangle
+            .hit(&ray(Vec3::new(0.6, 0.6, 2.0), down), 0.001, f64::INFINITY)
+            .is_none());
+        // Out of the [t_min; t_max] range, and behind the origin.
+        assert!(triangle
+            .hit(&ray(Vec3::new(0.25, 0.25, 2.0), down), 0.001, 1.0)
+            .is_none());
+        assert!(triangle
+            .hit(
+                &ray(Vec3::new(0.25, 0.25, -2.0), down),
+                0.001,
+                f64::INFINITY
+            )
+            .is_none());
+        // Parallel to the triangle.
+        assert!(triangle
+            .hit(
+                &ray(Vec3::new(-1.0, 0.25, 0.0), Vec3::new(1.0, 0.0, 0.0)),
+                0.001,
+                f64::INFINITY
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn shared_edges_have_no_holes() {
+        let a = Vec3::new(0.0, 0.0, 0.0);
+        let b = Vec3::new(1.0, 0.0, 0.0);
+        let c = Vec3::new(1.0, 1.0, 0.0);
+        let d = Vec3::new(0.0, 1.0, 0.0);
+        let halves = [triangle([a, b, c]), triangle([a, c, d])];
+        for i in 0..=10 {
+            let x = i as f64 / 10.0;
+            let diagonal = ray(Vec3::new(x, x, 1.0), Vec3::new(0.0, 0.0, -1.0));
+            assert!(halves
+                .iter()
+                .any(|half| half.hit(&diagonal, 0.001, f64::INFINITY).is_some()));
+        }
+    }
+
+    // Fraction of parallel rays, 3 units long inside the medium, that go
+    // through without colliding.
+    fn transmittance(majorant: f64, density_ratio: impl Fn(&Vec3) -> f64) -> f64 {
+        let phase_function = Isotropic {
+            albedo: Arc::new(SolidColor::new(0.5, 0.5, 0.5)),
+            emission: None,
+        };
+        let count = 20000;
+        let escaped = (0..count)
+            .filter(|&i| {
+                // A direction of length 2, distances must not be in t units.
+                let ray = ray(
+                    Vec3::new(0.0, i as f64 * 1e-4, 0.0),
+                    Vec3::new(2.0, 0.0, 0.0),
+                );
+                let hit = delta_tracking(&ray, 0.0, 1.5, majorant, &density_ratio, &phase_function);
+                if let Some(record) = &hit {
+                    assert!(record.t > 0.0 && record.t < 1.5);
+                    assert!(density_ratio(&record.p) > 0.0);
+                }
+                hit.is_none()
+            })
+            .count();
+        escaped as f64 / count as f64
+    }
+
+    #[test]
+    fn delta_tracking_follows_beer_lambert() {
+        let expected = (-0.5f64 * 3.0).exp();
+        assert!((transmittance(0.5, |_| 1.0) - expected).abs() < 0.015);
+        // Null collisions against a looser majorant don't change the result.
+        assert!((transmittance(2.0, |_| 0.25) - expected).abs() < 0.015);
+        // Only the dense part of the field, past x = 1, stops rays.
+        let expected = (-0.5f64 * 2.0).exp();
+        let step = |p: &Vec3| if p.x < 1.0 { 0.0 } else { 1.0 };
+        assert!((transmittance(0.5, step) - expected).abs() < 0.015);
+        assert_eq!(transmittance(0.5, |_| 0.0), 1.0);
+    }
+
+    #[test]
+    fn delta_tracking_is_reproducible() {
+        let phase_function = Isotropic {
+            albedo: Arc::new(SolidColor::new(0.5, 0.5, 0.5)),
+            emission: None,
+        };
+        let ray = ray(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let sample = || delta_tracking(&ray, 0.0, 10.0, 0.5, |_| 0.5, &phase_function).map(|r| r.t);
+        let first = sample();
+        assert!(first.is_some());
+        assert!((0..10).all(|_| sample() == first));
+    }
 }
@@ -0,0 +1,216 @@
+use crate::environment::Environment;
+use crate::hittable::{collect_lights, Hittable};
+use crate::math::Vec3;
+use crate::pdf::{EnvironmentPdf, HittablePdf, MixturePdf, Pdf};
+use rand::RngCore;
+use std::sync::Arc;
+
+// The scene's lights, down to those nested in groups, sampled directly from the
+// surfaces they light. From a point, each light is picked as often as its
+// power over its squared distance, so the few lights that matter there get
+// most of the samples. The environment, being infinitely far, is picked as
+// often as its power alone.
+#[derive(Clone)]
+pub struct LightList {
+    lights: Vec<Light>,
+    environment: Option<(Arc<dyn Environment>, f64)>,
+}
+
+#[derive(Clone)]
+struct Light {
+    hittable: Arc<dyn Hittable>,
+    power: f64,
+    // Center and squared radius of the light's bounding sphere, if it has one.
+    bounds: Option<(Vec3, f64)>,
+}
+
+impl Light {
+    fn importance(&self, origin: &Vec3) -> f64 {
+        match self.bounds {
+            // Points inside the bounds are as close as can be told.
+            Some((center, radius_squared)) => {
+                let distance_squared = (center - origin).norm_squared();
+                self.power / distance_squared.max(radius_squared)
+            }
+            None => self.power,
+        }
+    }
+}
+
+// Directions towards one of the lights.
+pub enum LightPdf<'a> {
+    Hittable(HittablePdf<'a>),
+    Environment(EnvironmentPdf<'a>),
+}
+
+impl Pdf for LightPdf<'_> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        match self {
+            LightPdf::Hittable(pdf) => pdf.value(direction),
+            LightPdf::Environment(pdf) => pdf.value(direction),
+        }
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        match self {
+            LightPdf::Hittable(pdf) => pdf.generate(rng),
+            LightPdf::Environment(pdf) => pdf.generate(rng),
+        }
+    }
+}
+
+impl LightList {
+    // A black environment isn't worth sampling.
+    pub fn new(
+        objects: &[Arc<dyn Hittable>],
+        environment: Option<Arc<dyn Environment>>,
+    ) -> LightList {
+        LightList {
+            lights: collect_lights(objects)
+                .into_iter()
+                .map(|object| Light {
+                    power: object.power(),
+                    bounds: object.bounding_box(0.0, 1.0).map(|bbox| {
+                        let half_diagonal = (bbox.max - bbox.min) / 2.0;
+                        (bbox.min + half_diagonal, half_diagonal.norm_squared())
+                    }),
+                    hittable: object,
+                })
+                .collect(),
+            environment: environment
+                .map(|environment| {
+                    let power = environment.power();
+                    (environment, power)
+                })
+                .filter(|(_, power)| *power > 0.0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len() + self.environment.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Directions from origin towards the lights.
+    pub fn pdf(&self, origin: &Vec3) -> MixturePdf<LightPdf<'_>> {
+        let mut components: Vec<(f64, LightPdf<'_>)> = self
+            .lights
+            .iter()
+            .map(|light| {
+                let pdf = HittablePdf {
+                    origin: *origin,
+                    hittable: light.hittable.as_ref(),
+                };
+                (light.importance(origin), LightPdf::Hittable(pdf))
+            })
+            .collect();
+        if let Some((environment, power)) = &self.environment {
+            let pdf = EnvironmentPdf {
+                environment: environment.as_ref(),
+            };
+            components.push((*power, LightPdf::Environment(pdf)));
+        }
+
+        let total: f64 = components.iter().map(|(importance, _)| importance).sum();
+        // Lights whose power can't be estimated are picked uniformly.
+        let uniform = 1.0 / components.len() as f64;
+        for (weight, _) in components.iter_mut() {
+            *weight = if total > 0.0 && total.is_finite() {
+                *weight / total
+            } else {
+                uniform
+            };
+        }
+        MixturePdf::new(components)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::{HittableList, Sphere};
+    use crate::material::DiffuseLight;
+    use crate::pdf::SpherePdf;
+    use crate::render::Ray;
+    use crate::texture::SolidColor;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use std::f64::consts::PI;
+
+    fn light(center: Vec3, radiance: f64) -> Arc<dyn Hittable> {
+        Arc::new(Sphere {
+            center,
+            radius: 0.5,
+            material: Arc::new(DiffuseLight {
+                emissive: Arc::new(SolidColor::new(radiance, radiance, radiance)),
+            }),
+        })
+    }
+
+    // From the origin: a far light along x, a close one along y and a far,
+    // bright one along z, nested in a group.
+    fn lights() -> (Vec<Arc<dyn Hittable>>, LightList) {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let group: Arc<dyn Hittable> = Arc::new(HittableList::from_slice(
+            &[light(Vec3::new(0.0, 0.0, 10.0), 4.0)],
+            0.0,
+            1.0,
+            &mut rng,
+        ));
+        let objects = vec![
+            light(Vec3::new(10.0, 0.0, 0.0), 1.0),
+            light(Vec3::new(0.0, 2.0, 0.0), 1.0),
+            group,
+        ];
+        let lights = LightList::new(&objects, None);
+        (objects, lights)
+    }
+
+    #[test]
+    fn close_and_bright_lights_are_picked_more() {
+        let (_, lights) = lights();
+        assert_eq!(lights.len(), 3);
+        let pdf = lights.pdf(&Vec3::zeros());
+        let mut rng = SmallRng::seed_from_u64(1);
+        let count = 30000;
+        let mut picks = [0; 3];
+        for _ in 0..count {
+            let direction = pdf.generate(&mut rng);
+            let axis = direction.iamax();
+            assert!(direction[axis] > 0.0);
+            picks[axis] += 1;
+        }
+        // Importances of 1, 25 and 4, in power over squared distance.
+        for (picked, expected) in picks.iter().zip([1.0, 25.0, 4.0]) {
+            let fraction = *picked as f64 / count as f64;
+            assert!((fraction - expected / 30.0).abs() < 0.01, "{:?}", picks);
+        }
+    }
+
+    #[test]
+    fn light_pdfs_integrate_to_one() {
+        let (objects, lights) = lights();
+        let origin = Vec3::new(0.5, -0.5, 0.5);
+        let pdf = lights.pdf(&origin);
+        let mut rng = SmallRng::seed_from_u64(2);
+        let count = 200000;
+        let sum: f64 = (0..count)
+            .map(|_| pdf.value(&SpherePdf.generate(&mut rng)))
+            .sum();
+        let integral = sum * 4.0 * PI / count as f64;
+        assert!((integral - 1.0).abs() < 0.05, "integral {}", integral);
+        // The directions it picks land on a light.
+        let world = HittableList::from_slice(&objects, 0.0, 1.0, &mut rng);
+        for _ in 0..100 {
+            let ray = Ray {
+                origin,
+                direction: pdf.generate(&mut rng),
+                time: 0.0,
+            };
+            assert!(world.hit(&ray, 0.001, f64::INFINITY).is_some());
+        }
+    }
+}
@@ -1,62 +1,147 @@
 mod args;
+mod atmosphere;
+mod bvh;
 mod colors;
+mod environment;
+mod gltf_import;
 mod hittable;
+mod lights;
 mod material;
 mod math;
+mod mesh;
 mod noise;
+mod obj;
+mod pdf;
+mod ply;
+mod point_cloud;
 mod render;
+mod scene;
+mod scenes;
 mod scheduler;
+mod sky;
 mod texture;
+mod tonemap;
+mod voxel;
 mod writers;
 
-use crate::noise::Perlin;
 use args::TracerArgs;
+use bvh::FlatBvh;
 use hittable::*;
+use lights::LightList;
 use material::*;
 use math::*;
+use pdf::Pdf;
 use rand::rngs::SmallRng;
-use rand::RngCore;
-use rand::SeedableRng;
-use rand_distr::{Distribution, Uniform};
+use rand::{RngCore, SeedableRng};
 use render::*;
+use scene::Scene;
+use scenes::{find_builtin_scene, BUILTIN_SCENES, DEFAULT_SCENE};
 use scheduler::Scheduler;
-use std::fs::File;
-use std::io::stdout;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdout, BufWriter, Write};
 use std::path::Path;
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::time::Instant;
-use texture::*;
+use tonemap::{find_tone_mapper, PostProcess};
 use writers::*;
 
-fn _sky_gradient(dir: &Vec3) -> Vec3 {
-    let unit_dir = dir.normalize();
-    let t = 0.5 * (unit_dir.y + 1.0);
-    Vec3::new(1.0, 1.0, 1.0).lerp(&Vec3::new(0.5, 0.7, 1.0), t)
+// Power heuristic (Veach, 1997) weight of a sample picked with the first
+// density, when the second one could have picked it too.
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let squared = pdf * pdf;
+    squared / (squared + other_pdf * other_pdf)
 }
 
+// emission_weight scales the light emitted where the ray lands, or the
+// environment's if it leaves the scene, for rays whose direction could also
+// have been picked by sampling the lights.
 fn ray_color(
     ray: Ray,
-    background: &Vec3,
-    hittable: &dyn Hittable,
+    world: &World,
     depth: u16,
+    emission_weight: f64,
     rng: &mut impl RngCore,
 ) -> Vec3 {
     if depth == 0 {
         return Vec3::zeros();
     }
 
-    if let Some(hit) = hittable.hit(&ray, 0.01, f64::INFINITY) {
-        let emitted = hit.material.emitted(hit.u, hit.v, &hit.p);
-        return match hit.material.scatter(&ray, &hit, rng) {
-            Some((outgoing_ray, attenuation)) => {
-                let color = ray_color(outgoing_ray, background, hittable, depth - 1, rng);
-                emitted + color.component_mul(&attenuation)
+    count_ray();
+    if let Some(hit) = world.hit(&ray, 0.01, rng) {
+        // Surfaces emit where they're hit, glowing media where the ray
+        // collides inside them.
+        let emitted = hit.material.emitted(hit.u, hit.v, &hit.p) * emission_weight;
+        let record = match hit.material.scatter(&ray, &hit, rng) {
+            Some(record) => record,
+            None => return emitted,
+        };
+        let pdf = match record.scattered {
+            Scattered::Specular(outgoing_ray) => {
+                let color = ray_color(outgoing_ray, world, depth - 1, 1.0, rng);
+                return emitted + color.component_mul(&record.attenuation);
             }
-            None => emitted,
+            Scattered::Pdf(pdf) => pdf,
+        };
+
+        let outgoing_ray = Ray {
+            origin: hit.p,
+            direction: pdf.generate(rng),
+            time: ray.time,
         };
+        let pdf_value = pdf.value(&outgoing_ray.direction);
+        if pdf_value <= 0.0 {
+            return emitted;
+        }
+        let weight = hit.material.scattering_pdf(&ray, &hit, &outgoing_ray) / pdf_value;
+
+        // Lit by sampling the lights too, unless the path ends there. Both
+        // samples are weighed by multiple importance sampling.
+        let mut direct = Vec3::zeros();
+        let mut next_emission_weight = 1.0;
+        if depth > 1 && !world.lights.is_empty() {
+            let light_pdf = world.lights.pdf(&hit.p);
+            direct = sample_lights(&ray, &hit, &light_pdf, pdf.as_ref(), world, rng);
+            next_emission_weight =
+                power_heuristic(pdf_value, light_pdf.value(&outgoing_ray.direction));
+        }
+        let color = ray_color(outgoing_ray, world, depth - 1, next_emission_weight, rng);
+        return emitted + (direct + color * weight).component_mul(&record.attenuation);
+    }
+    world.background(&ray.direction) * emission_weight
+}
+
+// Next event estimation: the light arriving from a direction picked towards
+// the lights, whatever it meets first, over the attenuation. material_pdf
+// is the distribution the material picks directions from.
+fn sample_lights(
+    ray: &Ray,
+    hit: &HitRecord,
+    light_pdf: &impl Pdf,
+    material_pdf: &dyn Pdf,
+    world: &World,
+    rng: &mut impl RngCore,
+) -> Vec3 {
+    let shadow_ray = Ray {
+        origin: hit.p,
+        direction: light_pdf.generate(rng),
+        time: ray.time,
+    };
+    let light_pdf_value = light_pdf.value(&shadow_ray.direction);
+    let scattering_pdf = hit.material.scattering_pdf(ray, hit, &shadow_ray);
+    if light_pdf_value <= 0.0 || scattering_pdf <= 0.0 {
+        return Vec3::zeros();
     }
-    *background
+
+    count_ray();
+    let emitted = match world.hit(&shadow_ray, 0.01, rng) {
+        Some(light_hit) => light_hit
+            .material
+            .emitted(light_hit.u, light_hit.v, &light_hit.p),
+        None => world.background(&shadow_ray.direction),
+    };
+    let weight = power_heuristic(light_pdf_value, material_pdf.value(&shadow_ray.direction));
+    emitted * (weight * scattering_pdf / light_pdf_value)
 }
 
 // TODO Adapt to add the background and emitted.
@@ -67,9 +152,24 @@ fn _ray_color_loop(ray: Ray, hittable: &dyn Hittable, depth: u16, rng: &mut impl
     for _n in 0..depth {
         if let Some(hit) = hittable.hit(&current_ray, 0.01, f64::INFINITY) {
             match hit.material.scatter(&current_ray, &hit, rng) {
-                Some((outgoing_ray, attenuation)) => {
-                    accumulated_color = accumulated_color.component_mul(&attenuation);
-                    current_ray = outgoing_ray;
+                Some(record) => {
+                    accumulated_color = accumulated_color.component_mul(&record.attenuation);
+                    current_ray = match record.scattered {
+                        Scattered::Specular(outgoing_ray) => outgoing_ray,
+                        Scattered::Pdf(pdf) => {
+                            let outgoing_ray = Ray {
+                                origin: hit.p,
+                                direction: pdf.generate(rng),
+                                time: current_ray.time,
+                            };
+                            let weight =
+                                hit.material
+                                    .scattering_pdf(&current_ray, &hit, &outgoing_ray)
+                                    / pdf.value(&outgoing_ray.direction);
+                            accumulated_color *= weight;
+                            outgoing_ray
+                        }
+                    };
                 }
                 // Ray was absorbed, stop.
                 None => return Vec3::zeros(),
@@ -87,356 +187,96 @@ fn _ray_color_loop(ray: Ray, hittable: &dyn Hittable, depth: u16, rng: &mut impl
     accumulated_color
 }
 
-fn earth(center: Vec3, radius: f64) -> Arc<Sphere> {
-    let earth_texture = Arc::new(ImageTexture::from_path("data/longlat.png"));
-    let earth_mat = Arc::new(Lambertian {
-        albedo: earth_texture,
-    });
-    Arc::new(Sphere {
-        center,
-        radius,
-        material: earth_mat,
-    })
-}
-
-// Hehehe
-fn flat_earth(min: Vec2, max: Vec2, k: f64) -> Arc<XzPlane> {
-    let earth_texture = Arc::new(ImageTexture::from_path("data/longlat.png"));
-    let earth_mat = Arc::new(Lambertian {
-        albedo: earth_texture,
-    });
-    Arc::new(XzPlane {
-        min,
-        max,
-        k,
-        material: earth_mat,
-    })
+// Checks that a file can be written at path, without truncating it as the
+// scene may still fail to load.
+fn check_output_path(path: &Path) -> io::Result<()> {
+    if path.exists() {
+        return OpenOptions::new().write(true).open(path).map(|_| ());
+    }
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the parent directory doesn't exist",
+        ));
+    }
+    Ok(())
 }
 
-fn _wave_scene() -> BvhNode {
-    let lambertian: Arc<dyn Material> = Arc::new(Lambertian {
-        albedo: Arc::new(SolidColor::new(0.2, 0.4, 0.6)),
-    });
-
-    let checker = Arc::new(Checkerboard {
-        albedo_odd: Arc::new(SolidColor::new(0.2, 0.4, 0.6)),
-        albedo_even: Arc::new(SolidColor::new(0.6, 0.6, 0.2)),
-    });
-
-    let lambertian_2: Arc<dyn Material> = Arc::new(Lambertian { albedo: checker });
-    let metal: Arc<dyn Material> = Arc::new(Metal {
-        albedo: Arc::new(SolidColor::new(0.7, 0.6, 0.5)),
-        roughness: 0.0,
-    });
-    let glass: Arc<dyn Material> = Arc::new(Dielectric { ior: 1.5 });
+fn main() -> ExitCode {
+    let arguments = match TracerArgs::from_std() {
+        Ok(arguments) => arguments,
+        Err(code) => return code,
+    };
 
-    // No need for a hittable_list. A simple vector is largely enough for the process.
-    // A scene load/save could be interesting to add.
-    let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
-    objects.push(Arc::new(Sphere {
-        center: Vec3::new(0.0, -1005.0, 0.0),
-        radius: 1000.0,
-        material: lambertian_2,
-    }));
-    for y in (-5..5).step_by(3) {
-        for x in -10..10 {
-            let r = f64::hypot(x as f64, y as f64);
-            let begin = Vec3::new((x * 3) as f64, 0.0, (y * 3) as f64);
-            objects.push(Arc::new(MovingSphere {
-                center_begin: begin,
-                center_end: begin + Vec3::new(0.0, 1.0, 0.0),
-                radius: r / 5. + 0.2,
-                material: lambertian.clone(),
-                time_begin: 0.0,
-                time_end: 1.0,
-            }));
-            let begin = Vec3::new((x * 3) as f64, 0.0, (y * 3 + 3) as f64);
-            objects.push(Arc::new(MovingSphere {
-                center_begin: begin,
-                center_end: begin + Vec3::new(0.0, 1.0, 0.0),
-                radius: r / 5. + 0.2,
-                material: metal.clone(),
-                time_begin: 0.0,
-                time_end: 1.0,
-            }));
-            let begin = Vec3::new((x * 3) as f64, 0.0, (y * 3 + 6) as f64);
-            objects.push(Arc::new(MovingSphere {
-                center_begin: begin,
-                center_end: begin + Vec3::new(0.0, 1.0, 0.0),
-                radius: r / 5. + 0.2,
-                material: glass.clone(),
-                time_begin: 0.0,
-                time_end: 1.0,
-            }));
+    if arguments.list_scenes {
+        for builtin in BUILTIN_SCENES.iter() {
+            println!("{:<14} {}", builtin.name, builtin.description);
         }
+        return ExitCode::SUCCESS;
     }
 
-    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
-    BvhNode::from_slice(&objects[..], 0.0, f64::INFINITY, &mut rng)
-}
-
-fn _book_cover_scene() -> BvhNode {
-    let mut world_elements: Vec<Arc<dyn Hittable>> = vec![];
-    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
-
-    let _checker = Arc::new(Checkerboard {
-        albedo_odd: Arc::new(SolidColor::new(0.2, 0.4, 0.6)),
-        albedo_even: Arc::new(SolidColor::new(0.6, 0.6, 0.2)),
-    });
-
-    // let noise = Arc::new(MarbleNoise {
-    //     perlin: Perlin::new(&mut rng),
-    //     scale: 4.,
-    //     depth: 5,
-    // });
-
-    // let ground_mat = Arc::new(Lambertian { albedo: noise });
-    // world_elements.push(Arc::new(Sphere {
-    //     center: Vec3::new(0.0, -1000.0, 0.0),
-    //     radius: 1000.0,
-    //     material: ground_mat,
-    // }));
-    world_elements.push(flat_earth(Vec2::new(-20., -20.), Vec2::new(20., 20.), 0.));
-
-    let material_distribution = Uniform::from(0..4);
-
-    let uniform_dist = Uniform::from(0.0..1.0);
-    let metal_dist = Uniform::from(0.5..1.0);
-    let emissive_dist = Uniform::from(0.5..4.0);
-    let metal_roughness_dist = Uniform::from(0.0..0.5);
-    let position_dist = Uniform::from(-0.9..0.9);
-    let glass_mat: Arc<dyn Material> = Arc::new(Dielectric { ior: 1.5 });
-
-    let big_sphere_pos_1 = Vec3::new(0.0, 1.0, 0.0);
-    let big_sphere_pos_2 = Vec3::new(-4.0, 1.0, 0.0);
-    let big_sphere_pos_3 = Vec3::new(4.0, 1.0, 0.0);
-    for x in -11..11 {
-        for y in -11..11 {
-            let center = Vec3::new(
-                x as f64 + position_dist.sample(&mut rng),
-                0.2,
-                y as f64 + position_dist.sample(&mut rng),
+    let tone_mapper_name = arguments.tone_mapper.as_deref().unwrap_or("clamp");
+    let post_process = match find_tone_mapper(tone_mapper_name) {
+        Some(tone_mapper) => PostProcess {
+            exposure: arguments.exposure,
+            tone_mapper,
+        },
+        None => {
+            eprintln!(
+                "Unknown tone mapper {}, use clamp, reinhard or aces",
+                tone_mapper_name
             );
-            if (center - big_sphere_pos_1).norm() < 1.5 {
-                continue;
-            }
-            if (center - big_sphere_pos_2).norm() < 1.5 {
-                continue;
-            }
-            if (center - big_sphere_pos_3).norm() < 1.5 {
-                continue;
-            }
-            let selector = material_distribution.sample(&mut rng);
-            let material: Arc<dyn Material> = match selector {
-                0 => {
-                    let a1 = generate_vector(&uniform_dist, &mut rng);
-                    let a2 = generate_vector(&uniform_dist, &mut rng);
-                    let albedo = Arc::new(SolidColor::new(a1.x * a2.x, a1.y * a2.y, a1.z * a2.z));
-                    Arc::new(Lambertian { albedo })
-                }
-                1 => {
-                    let albedo = Arc::new(SolidColor {
-                        albedo: generate_vector(&metal_dist, &mut rng),
-                    });
-                    Arc::new(Metal {
-                        albedo,
-                        roughness: metal_roughness_dist.sample(&mut rng),
-                    })
-                }
-                2 => Arc::clone(&glass_mat),
-                3 => {
-                    let emissive = Arc::new(SolidColor {
-                        albedo: generate_vector(&emissive_dist, &mut rng),
-                    });
-                    Arc::new(DiffuseLight { emissive })
-                }
-                _ => panic!("Unreachable"),
-            };
-
-            world_elements.push(match selector {
-                0 => {
-                    let center_2 =
-                        center + Vec3::new(0.0, metal_roughness_dist.sample(&mut rng), 0.0);
-                    Arc::new(MovingSphere {
-                        center_begin: center,
-                        center_end: center_2,
-                        time_begin: 0.0,
-                        time_end: 1.0,
-                        radius: 0.2,
-                        material,
-                    })
-                }
-                1 | 2 | 3 => Arc::new(Sphere {
-                    center,
-                    radius: 0.2,
-                    material,
-                }),
-                _ => panic!("Unreachable"),
-            });
+            return ExitCode::FAILURE;
         }
-    }
-
-    let mat1: Arc<dyn Material> = Arc::new(Dielectric { ior: 1.5 });
-    world_elements.push(Arc::new(Sphere {
-        center: big_sphere_pos_1,
-        radius: 1.0,
-        material: Arc::clone(&mat1),
-    }));
-    world_elements.push(Arc::new(Sphere {
-        center: big_sphere_pos_1,
-        radius: -0.8,
-        material: mat1,
-    }));
-    world_elements.push(earth(big_sphere_pos_2, 1.0));
-    let mat2: Arc<dyn Material> = Arc::new(Lambertian {
-        albedo: Arc::new(SolidColor::new(0.4, 0.2, 0.1)),
-    });
-    world_elements.push(Arc::new(Sphere {
-        center: big_sphere_pos_2,
-        radius: 1.0,
-        material: mat2,
-    }));
-    let mat3: Arc<dyn Material> = Arc::new(Metal {
-        albedo: Arc::new(SolidColor::new(0.7, 0.6, 0.5)),
-        roughness: 0.0,
-    });
-    world_elements.push(Arc::new(Sphere {
-        center: big_sphere_pos_3,
-        radius: 1.0,
-        material: mat3,
-    }));
-
-    world_elements.push(Arc::new(Sphere {
-        center: Vec3::new(0.0, 10.0, 0.0),
-        radius: 2.0,
-        material: Arc::new(DiffuseLight {
-            emissive: Arc::new(SolidColor::new(5.0, 5.0, 5.0)),
-        }),
-    }));
-
-    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
-    BvhNode::from_slice(&world_elements[..], 0.0, f64::INFINITY, &mut rng)
-}
-
-fn cornell_box() -> BvhNode {
-    let mut objects: Vec<Arc<dyn Hittable>> = vec![];
-    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
-
-    let red = Arc::new(Lambertian {
-        albedo: Arc::new(SolidColor::new(0.65, 0.05, 0.05)),
-    });
-    let white = Arc::new(Lambertian {
-        albedo: Arc::new(SolidColor::new(0.73, 0.73, 0.73)),
-    });
-    let green = Arc::new(Lambertian {
-        albedo: Arc::new(SolidColor::new(0.12, 0.45, 0.15)),
-    });
-
-    let light = Arc::new(DiffuseLight {
-        emissive: Arc::new(SolidColor::new(15., 15., 15.)),
-    });
-
-    // let metal_02 = Arc::new(Metal {
-    //     albedo: Arc::new(SolidColor::new(0.8, 0.8, 0.8)),
-    //     roughness: 0.2,
-    // });
-    // let metal_05 = Arc::new(Metal {
-    //     albedo: Arc::new(SolidColor::new(0.8, 0.8, 0.8)),
-    //     roughness: 0.5,
-    // });
-    // let metal_08 = Arc::new(Metal {
-    //     albedo: Arc::new(SolidColor::new(0.8, 0.8, 0.8)),
-    //     roughness: 0.8,
-    // });
-
-    // Left and right
-    objects.push(Arc::new(YzPlane {
-        min: Vec2::new(0., 0.),
-        max: Vec2::new(555., 555.),
-        k: 555.,
-        material: green.clone(),
-    }));
-    objects.push(Arc::new(YzPlane {
-        min: Vec2::new(0., 0.),
-        max: Vec2::new(555., 555.),
-        k: 0.,
-        material: red.clone(),
-    }));
-    // Top and bottom
-    objects.push(Arc::new(XzPlane {
-        min: Vec2::new(0., 0.),
-        max: Vec2::new(555., 555.),
-        k: 0.,
-        material: white.clone(),
-    }));
-    objects.push(Arc::new(XzPlane {
-        min: Vec2::new(0., 0.),
-        max: Vec2::new(555., 555.),
-        k: 555.,
-        material: white.clone(),
-    }));
-    // Back
-    objects.push(Arc::new(XyPlane {
-        min: Vec2::new(0., 0.),
-        max: Vec2::new(555., 555.),
-        k: 555.,
-        material: white.clone(),
-    }));
-    // Light
-    objects.push(Arc::new(XzPlane {
-        min: Vec2::new(213., 227.),
-        max: Vec2::new(343., 342.),
-        k: 554.,
-        material: light.clone(),
-    }));
-    // Spheres
-    // objects.push(Arc::new(Sphere {
-    //     center: Vec3::new(139., 60., 284.),
-    //     radius: 60.,
-    //     material: metal_02.clone(),
-    // }));
-    // objects.push(Arc::new(Sphere {
-    //     center: Vec3::new(278., 60., 284.),
-    //     radius: 60.,
-    //     material: metal_05.clone(),
-    // }));
-    // objects.push(Arc::new(Sphere {
-    //     center: Vec3::new(417., 60., 284.),
-    //     radius: 60.,
-    //     material: metal_08.clone(),
-    // }));
-    // Cubes
-    let cube_mat = Mat4::new_rotation(Vec3::new(0., 1., 0.) * f64::to_radians(15.));
-    let cube_mat = cube_mat.append_translation(&Vec3::new(265., 0., 295.));
-
-    let cube = Arc::new(Cube::new(
-        Vec3::new(-82.5 * 0., 0., -82.5 * 0.),
-        Vec3::new(82.5 * 2., 330., 82.5 * 2.),
-        white.clone(),
-        &mut rng,
-    ));
-    objects.push(Arc::new(Transform::new(&cube_mat, cube)));
-
-    let cube_mat = Mat4::new_rotation(Vec3::new(0., 1., 0.) * f64::to_radians(-18.));
-    let cube_mat = cube_mat.append_translation(&Vec3::new(130., 0., 65.));
+    };
 
-    let cube = Arc::new(Cube::new(
-        Vec3::new(0., 0., 0.),
-        Vec3::new(165., 165., 165.),
-        white.clone(),
-        &mut rng,
-    ));
-    objects.push(Arc::new(Transform::new(&cube_mat, cube)));
+    if arguments.width == 0 || arguments.height == 0 {
+        eprintln!("The width and height must be positive");
+        return ExitCode::FAILURE;
+    }
 
-    BvhNode::from_slice(&objects[..], 0.0, f64::INFINITY, &mut rng)
-}
+    if !(1..=100).contains(&arguments.jpeg_quality) {
+        eprintln!("The JPEG quality goes from 1 to 100");
+        return ExitCode::FAILURE;
+    }
 
-fn main() {
-    let args_maybe = TracerArgs::from_std();
-    if let None = args_maybe {
-        return;
+    // The output is checked before rendering rather than lost after it.
+    let options = WriterOptions {
+        exr_float: arguments.exr_float,
+        jpeg_quality: arguments.jpeg_quality,
+    };
+    // Usual convention is that - uses stdout
+    let output_path = arguments.output_path.as_deref().filter(|path| *path != "-");
+    let format = match output_format(arguments.format.as_deref(), output_path) {
+        Some(format) => format,
+        None => {
+            eprintln!(
+                "{} has no extension, use --format to pick the output format",
+                output_path.unwrap_or_default()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let writer = match guess_output_format(format, &options) {
+        Some(writer) => writer,
+        None => {
+            eprintln!(
+                "Unknown output format {}, use ppm, ppm-binary, png, png16, jpg, tiff, bmp, exr, hdr or pfm",
+                format
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(path) = output_path {
+        if let Err(err) = check_output_path(Path::new(path)) {
+            eprintln!("{}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
     }
-    let arguments = args_maybe.unwrap();
 
     let max_depth = arguments.depth;
     let num_threads = arguments.num_threads;
@@ -444,27 +284,89 @@ fn main() {
     let render_width = arguments.width;
     let render_height = arguments.height;
     let aspect_ratio = render_width as f64 / render_height as f64;
-    let eye = Vec3::new(278., 278., -800.);
-    let target = Vec3::new(278., 278., 0.);
-    // let eye = Vec3::new(0.0, 2.0, -10.0);
-    // let target = Vec3::zeros();
-    // let world = Arc::new(book_cover_scene());
-    let world = Arc::new(cornell_box());
+    let before_load = Instant::now();
+    let scene = match (&arguments.scene_path, &arguments.scene_name) {
+        (Some(_), Some(_)) => {
+            eprintln!("--scene and --scene-file can't be used together");
+            return ExitCode::FAILURE;
+        }
+        (Some(scene_path), None) => match Scene::from_path(Path::new(scene_path)) {
+            Ok(scene) => scene,
+            Err(err) => {
+                eprintln!("{}: {}", scene_path, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, scene_name) => {
+            let scene_name = scene_name.as_deref().unwrap_or(DEFAULT_SCENE);
+            match find_builtin_scene(scene_name) {
+                Some(builtin) => (builtin.build)(),
+                None => {
+                    eprintln!(
+                        "Unknown scene {}, use --list-scenes to see the available ones",
+                        scene_name
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    };
+    // Includes building the meshes' own BVHs.
+    eprintln!(
+        "Scene loaded in {:.2} ms",
+        before_load.elapsed().as_secs_f64() * 1000.0
+    );
+
+    // Moving objects are bounded over the camera's shutter interval.
+    let (time_begin, time_end) = (scene.camera.time_begin, scene.camera.time_end);
+    let before_build = Instant::now();
+    let objects: Arc<dyn Hittable> = match arguments.bvh_builder.as_deref().unwrap_or("flat") {
+        "flat" => Arc::new(FlatBvh::new(&scene.objects, time_begin, time_end)),
+        "sah" => Arc::new(BvhNode::from_slice_sah(
+            &scene.objects,
+            time_begin,
+            time_end,
+        )),
+        "random" => {
+            let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
+            Arc::new(BvhNode::from_slice(
+                &scene.objects,
+                time_begin,
+                time_end,
+                &mut rng,
+            ))
+        }
+        builder => {
+            eprintln!("Unknown BVH builder {}, use flat, sah or random", builder);
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!(
+        "BVH built in {:.2} ms ({} objects)",
+        before_build.elapsed().as_secs_f64() * 1000.0,
+        scene.objects.len()
+    );
+
+    // The atmosphere fills the scene and the space up to the camera.
+    let atmosphere = scene.atmosphere.as_ref().and_then(|settings| {
+        let bounds = objects.bounding_box(time_begin, time_end)?;
+        let eye = AABB::new(scene.camera.eye, scene.camera.eye);
+        Some(settings.build(bounds.union(&eye)))
+    });
+    let lights = LightList::new(&scene.objects, scene.environment.clone());
+    eprintln!("{} lights sampled directly", lights.len());
+    let world = World {
+        objects,
+        atmosphere,
+        lights,
+        environment: scene.environment,
+    };
+
     let before = Instant::now();
     // Camera derives Copy+Clone, the structure will be copied to the threads.
-    let cam = Camera::new(
-        eye,
-        target,
-        Vec3::new(0.0, 1.0, 0.0),
-        40., //60.,
-        aspect_ratio,
-        0.0, // Aperture
-        (eye - target).norm(),
-        0.0,
-        1.0,
-    );
+    let cam = scene.camera.build(aspect_ratio);
 
-    let final_buffer = Scheduler::run_threaded(
+    let (final_buffer, rays) = Scheduler::run_threaded(
         &world,
         &cam,
         num_iterations,
@@ -474,36 +376,117 @@ fn main() {
         max_depth,
     );
 
-    eprintln!("Render took {} seconds", before.elapsed().as_secs());
+    let render_time = before.elapsed().as_secs_f64();
+    eprintln!(
+        "Render took {:.2} seconds, {} rays ({:.2} Mrays/s)",
+        render_time,
+        rays,
+        rays as f64 / render_time / 1e6
+    );
 
-    let mut output_file: Box<dyn Write> = match &arguments.output_path {
-        None => Box::new(stdout()),
-        Some(path) => {
-            // Usual convention is that - uses stdout
-            if path == "-" {
-                Box::new(stdout())
-            } else {
-                Box::new(File::create(path).unwrap())
+    // HDR formats keep the radiances, without exposure or tone mapping.
+    let pixels = if writer.is_linear() {
+        final_buffer
+    } else {
+        post_process.apply(&final_buffer)
+    };
+    let output_name = output_path.unwrap_or("stdout");
+    let mut output_file: Box<dyn Write> = match output_path {
+        None => Box::new(BufWriter::new(stdout())),
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                return ExitCode::FAILURE;
             }
-        }
+        },
     };
+    let written = writer
+        .write_to(output_file.as_mut(), &pixels, render_width, render_height)
+        .and_then(|()| output_file.flush().map_err(Into::into));
+    if let Err(err) = written {
+        eprintln!("{}: {}", output_name, err);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use texture::SolidColor;
+
+    #[test]
+    fn power_heuristic_weights_add_up_to_one() {
+        for (a, b) in [(1.0, 1.0), (0.3, 2.0), (5.0, 0.0), (1e-3, 1e3)] {
+            assert!((power_heuristic(a, b) + power_heuristic(b, a) - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+        assert_eq!(power_heuristic(2.0, 0.0), 1.0);
+    }
 
-    let extension = arguments.output_path.unwrap_or_default();
-    let path = Path::new(&extension);
-    if let Some(boxed_writer) = guess_output_format(
-        &path
-            .extension()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default(),
-    ) {
-        boxed_writer.write_to(
-            output_file.as_mut(),
-            &final_buffer,
-            render_width,
-            render_height,
-            // Sigh, see ImageWriter's todo
-            num_iterations as u32,
-        );
+    // A grey floor lit by a sphere of radius 0.5 and radiance 4, 2 units over
+    // the origin. The irradiance there is pi * 4 * (0.5 / 2)^2, and the floor
+    // reflects 0.5 / pi of it.
+    fn lit_floor(sample_lights: bool) -> Vec3 {
+        let floor: Arc<dyn Hittable> = Arc::new(Triangle {
+            vertices: [
+                Vec3::new(-100.0, 0.0, -100.0),
+                Vec3::new(-100.0, 0.0, 100.0),
+                Vec3::new(100.0, 0.0, 0.0),
+            ],
+            normals: None,
+            uvs: None,
+            material: Arc::new(Lambertian {
+                albedo: Arc::new(SolidColor::new(0.5, 0.5, 0.5)),
+            }),
+        });
+        let light: Arc<dyn Hittable> = Arc::new(Sphere {
+            center: Vec3::new(0.0, 2.0, 0.0),
+            radius: 0.5,
+            material: Arc::new(DiffuseLight {
+                emissive: Arc::new(SolidColor::new(4.0, 4.0, 4.0)),
+            }),
+        });
+        let objects = vec![floor, light];
+        let lights = if sample_lights {
+            LightList::new(&objects, None)
+        } else {
+            LightList::new(&[], None)
+        };
+        let world = World {
+            objects: Arc::new(HittableList::from_slice(
+                &objects,
+                0.0,
+                1.0,
+                &mut SmallRng::seed_from_u64(0),
+            )),
+            atmosphere: None,
+            lights,
+            environment: None,
+        };
+
+        let mut rng = SmallRng::seed_from_u64(1);
+        let count = 20000;
+        let sum: Vec3 = (0..count)
+            .map(|_| {
+                let down = Ray {
+                    origin: Vec3::new(0.0, 1.0, 0.0),
+                    direction: Vec3::new(0.0, -1.0, 0.0),
+                    time: 0.0,
+                };
+                ray_color(down, &world, 2, 1.0, &mut rng)
+            })
+            .sum();
+        sum / count as f64
+    }
+
+    #[test]
+    fn light_and_material_samples_are_weighed_without_bias() {
+        let expected = 0.5 * 4.0 * 0.25 * 0.25;
+        for sample_lights in [false, true] {
+            let color = lit_floor(sample_lights);
+            assert!((color.x - expected).abs() < 0.01, "{:?}", color);
+        }
     }
 }
@@ -1,15 +1,42 @@
+use crate::pdf::*;
 use crate::texture::Texture;
 use crate::Vec3;
 use crate::{HitRecord, Ray};
 use nalgebra::Vector3;
 use rand::RngCore;
-use rand_distr::{Distribution, Uniform, UnitSphere};
+use rand_distr::{Distribution, Uniform};
+use std::f64::consts::PI;
 use std::sync::Arc;
 
+// How a ray leaves a material. Specular bounces only go in one direction,
+// which is already picked. The others give the distribution to pick it from,
+// so other distributions, like the lights', can be mixed in and weighed.
+pub enum Scattered {
+    Specular(Ray),
+    Pdf(Box<dyn Pdf>),
+}
+
+pub struct ScatterRecord {
+    pub attenuation: Vec3,
+    pub scattered: Scattered,
+}
+
 pub trait Material: Sync + Send {
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3;
 
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<(Ray, Vec3)>;
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<ScatterRecord>;
+
+    // How much of the attenuation goes in a direction, as a density: a
+    // sample picked with any Pdf is weighed by this over the Pdf's value.
+    // Only called for non-specular scattering.
+    fn scattering_pdf(&self, _ray: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        0.0
+    }
+
+    // Emissive materials make their hittables lights, sampled directly.
+    fn is_emissive(&self) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian {
@@ -29,26 +56,36 @@ pub struct DiffuseLight {
     pub emissive: Arc<dyn Texture>,
 }
 
+// Scatters uniformly in every direction, the phase function of volumes.
+// Glowing volumes also emit at every point a ray collides with them.
+pub struct Isotropic {
+    pub albedo: Arc<dyn Texture>,
+    pub emission: Option<Arc<dyn Texture>>,
+}
+
+// The Henyey-Greenstein phase function, scattering forward for a positive
+// anisotropy, backward for a negative one and uniformly at 0.
+pub struct HenyeyGreenstein {
+    pub albedo: Arc<dyn Texture>,
+    pub anisotropy: f64,
+}
+
 impl Material for Lambertian {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<(Ray, Vec3)> {
-        let v = UnitSphere.sample(rng);
-        let mut scatter_direction = rec.normal + Vector3::from_row_slice(&v);
-
-        let ee_x = epsilon_equal(scatter_direction.x, 0.0, 1.0e-8);
-        let ee_y = epsilon_equal(scatter_direction.y, 0.0, 1.0e-8);
-        let ee_z = epsilon_equal(scatter_direction.z, 0.0, 1.0e-8);
-        if ee_x && ee_y && ee_z {
-            scatter_direction = rec.normal;
-        }
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        rec: &HitRecord,
+        _rng: &mut dyn RngCore,
+    ) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: rec.albedo(self.albedo.as_ref()),
+            scattered: Scattered::Pdf(Box::new(CosinePdf::new(&rec.normal))),
+        })
+    }
 
-        Some((
-            Ray {
-                origin: rec.p,
-                direction: scatter_direction,
-                time: ray.time,
-            },
-            self.albedo.value(rec.u, rec.v, &rec.p),
-        ))
+    fn scattering_pdf(&self, _ray: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let cosine = rec.normal.dot(&scattered.direction.normalize());
+        cosine.max(0.0) / PI
     }
 
     fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Vec3 {
@@ -56,10 +93,10 @@ impl Material for Lambertian {
     }
 }
 
+// Rough metals scatter from a distribution and can be lit by sampling the
+// lights, perfect mirrors only reflect.
 impl Material for Metal {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<(Ray, Vec3)> {
-        let v: [f64; 3] = UnitSphere.sample(rng);
-
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, _rng: &mut dyn RngCore) -> Option<ScatterRecord> {
         let unit_direction = ray.direction.normalize();
 
         let refraction_ratio = {
@@ -71,30 +108,48 @@ impl Material for Metal {
             }
         };
         let cos_theta = (-unit_direction).dot(&rec.normal).min(1.0);
-        let albedo_at_point = self.albedo.value(rec.u, rec.v, &rec.p);
+        let albedo_at_point = rec.albedo(self.albedo.as_ref());
         let attenuation = albedo_at_point.lerp(
             &Vec3::new(1.0, 1.0, 1.0),
             schlick_reflectance(cos_theta, refraction_ratio),
         );
 
-        let reflected = reflect(&ray.direction.normalize(), &rec.normal);
-        let scattered = Ray {
-            origin: rec.p,
-            direction: (reflected + self.roughness * Vec3::new(v[0], v[1], v[2])).normalize(),
-            time: ray.time,
-        };
-        if scattered.direction.dot(&rec.normal) > 0.0 {
-            return Some((scattered, attenuation));
+        let reflected = reflect(&unit_direction, &rec.normal);
+        if self.roughness > 0.0 {
+            return Some(ScatterRecord {
+                attenuation,
+                scattered: Scattered::Pdf(Box::new(FuzzyReflectionPdf::new(
+                    &reflected,
+                    self.roughness,
+                ))),
+            });
         }
-        None
+        Some(ScatterRecord {
+            attenuation,
+            scattered: Scattered::Specular(Ray {
+                origin: rec.p,
+                direction: reflected,
+                time: ray.time,
+            }),
+        })
     }
+
+    // Directions going under the surface are absorbed.
+    fn scattering_pdf(&self, ray: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        if scattered.direction.dot(&rec.normal) <= 0.0 {
+            return 0.0;
+        }
+        let reflected = reflect(&ray.direction.normalize(), &rec.normal);
+        FuzzyReflectionPdf::new(&reflected, self.roughness).value(&scattered.direction)
+    }
+
     fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Vec3 {
         Vec3::zeros()
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<(Ray, Vec3)> {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<ScatterRecord> {
         let attenuation = Vec3::from_element(1.0);
         let unit_direction = ray.direction.normalize();
 
@@ -124,7 +179,10 @@ impl Material for Dielectric {
             direction: outward,
             time: ray.time,
         };
-        Some((scattered, attenuation))
+        Some(ScatterRecord {
+            attenuation,
+            scattered: Scattered::Specular(scattered),
+        })
     }
 
     fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Vec3 {
@@ -133,17 +191,67 @@ impl Material for Dielectric {
 }
 
 impl Material for DiffuseLight {
-    fn scatter(&self, _ray: &Ray, _rec: &HitRecord, _rng: &mut dyn RngCore) -> Option<(Ray, Vec3)> {
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        _rec: &HitRecord,
+        _rng: &mut dyn RngCore,
+    ) -> Option<ScatterRecord> {
         None
     }
 
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
         self.emissive.value(u, v, p)
     }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
 }
 
-fn epsilon_equal(a: f64, b: f64, epsilon: f64) -> bool {
-    (a - b).abs() < epsilon
+impl Material for Isotropic {
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        rec: &HitRecord,
+        _rng: &mut dyn RngCore,
+    ) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: rec.albedo(self.albedo.as_ref()),
+            scattered: Scattered::Pdf(Box::new(SpherePdf)),
+        })
+    }
+
+    fn scattering_pdf(&self, _ray: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+
+    fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        match &self.emission {
+            Some(emission) => emission.value(u, v, p),
+            None => Vec3::zeros(),
+        }
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, _rng: &mut dyn RngCore) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: rec.albedo(self.albedo.as_ref()),
+            scattered: Scattered::Pdf(Box::new(HenyeyGreensteinPdf::new(
+                &ray.direction,
+                self.anisotropy,
+            ))),
+        })
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, _rec: &HitRecord, scattered: &Ray) -> f64 {
+        HenyeyGreensteinPdf::new(&ray.direction, self.anisotropy).value(&scattered.direction)
+    }
+
+    fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+        Vec3::zeros()
+    }
 }
 
 fn reflect(i: &Vec3, n: &Vec3) -> Vec3 {
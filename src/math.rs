@@ -5,13 +5,8 @@ use rand::RngCore;
 pub type Vec2 = nalgebra::Vector2<f64>;
 pub type Vec3 = nalgebra::Vector3<f64>;
 pub type Vec4 = nalgebra::Vector4<f64>;
-pub type Mat3 = nalgebra::Matrix3<f64>;
 pub type Mat4 = nalgebra::Matrix4<f64>;
 
-pub fn vpowf(v: &Vec3, factor: f64) -> Vec3 {
-    v.map(|f| f.powf(factor))
-}
-
 pub fn vmin(a: &Vec3, b: &Vec3) -> Vec3 {
     a.zip_map(b, f64::min)
 }
@@ -21,6 +16,7 @@ pub fn vmax(a: &Vec3, b: &Vec3) -> Vec3 {
 }
 
 #[derive(Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct AABB {
     pub min: Vec3,
     pub max: Vec3,
@@ -44,6 +40,16 @@ impl AABB {
         AABB { min, max }
     }
 
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let extent = self.max - self.min;
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+
+    // Narrows [t_min; t_max] to the part of the ray inside one slab.
     fn test_component(
         min_component: f64,
         max_component: f64,
@@ -51,7 +57,7 @@ impl AABB {
         ray_dir_component: f64,
         t_min: f64,
         t_max: f64,
-    ) -> bool {
+    ) -> Option<(f64, f64)> {
         let ray_inv = ray_dir_component.recip();
 
         let mut t0 = (min_component - ray_pos_component) * ray_inv;
@@ -64,27 +70,36 @@ impl AABB {
         let t_min = f64::max(t0, t_min);
         let t_max = f64::min(t1, t_max);
         if t_max <= t_min {
-            return false;
+            return None;
         }
-        true
+        Some((t_min, t_max))
     }
 
     pub fn intersects(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
-        AABB::test_component(
+        self.hit_interval(ray, t_min, t_max).is_some()
+    }
+
+    // The part of [t_min; t_max] where the ray is inside the box.
+    pub fn hit_interval(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        // The slabs' intervals have to overlap, so each test works on what's
+        // left from the previous one.
+        let (t_min, t_max) = AABB::test_component(
             self.min.x,
             self.max.x,
             ray.origin.x,
             ray.direction.x,
             t_min,
             t_max,
-        ) && AABB::test_component(
+        )?;
+        let (t_min, t_max) = AABB::test_component(
             self.min.y,
             self.max.y,
             ray.origin.y,
             ray.direction.y,
             t_min,
             t_max,
-        ) && AABB::test_component(
+        )?;
+        AABB::test_component(
             self.min.z,
             self.max.z,
             ray.origin.z,
@@ -95,23 +110,34 @@ impl AABB {
     }
 }
 
+// Two vectors completing the unit vector n into an orthonormal basis (Duff et
+// al., 2017).
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1f64.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
 pub fn generate_vector(dist: &impl Distribution<f64>, rng: &mut impl RngCore) -> Vec3 {
     Vec3::new(dist.sample(rng), dist.sample(rng), dist.sample(rng))
 }
 
-// Kept for reference
-pub fn _trilinear_interpolation(c: [[[f64; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
+pub fn trilinear_interpolation(c: [[[f64; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
     let mut acc = 0.0;
-    for i in 0..2 {
-        for j in 0..2 {
-            for k in 0..2 {
+    for (i, plane) in c.iter().enumerate() {
+        for (j, row) in plane.iter().enumerate() {
+            for (k, value) in row.iter().enumerate() {
                 let fi = i as f64;
                 let fj = j as f64;
                 let fk = k as f64;
                 let il = fi * u + (1.0 - fi) * (1.0 - u);
                 let jl = fj * v + (1.0 - fj) * (1.0 - v);
                 let kl = fk * w + (1.0 - fk) * (1.0 - w);
-                acc += il * jl * kl * c[i][j][k];
+                acc += il * jl * kl * value;
             }
         }
     }
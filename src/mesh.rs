@@ -0,0 +1,183 @@
+use crate::bvh::IndexBvh;
+use crate::hittable::*;
+use crate::material::Material;
+use crate::math::*;
+use crate::{HitRecord, Ray};
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+use std::sync::Arc;
+
+// Vertex attributes, shared between all the meshes using them (e.g. the groups
+// of an OBJ file). Normals, UVs and colors, when present, are indexed like
+// positions.
+pub struct MeshBuffers {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<Vec2>>,
+    pub colors: Option<Vec<Vec3>>,
+}
+
+// A set of triangles with its own BVH over triangle indices, so a whole mesh
+// costs a single Hittable in the scene. Share it with an Arc to instance it
+// several times under different Transforms.
+pub struct TriangleMesh {
+    buffers: Arc<MeshBuffers>,
+    triangles: Vec<[u32; 3]>,
+    material: Arc<dyn Material>,
+    bvh: IndexBvh,
+    // Running sum of the triangles' areas when the mesh emits light, to pick
+    // them as often as their area.
+    light_areas: Vec<f64>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        buffers: Arc<MeshBuffers>,
+        triangles: Vec<[u32; 3]>,
+        material: Arc<dyn Material>,
+    ) -> TriangleMesh {
+        assert!(!triangles.is_empty(), "A mesh needs at least one triangle");
+        let vertex_count = buffers.positions.len();
+        assert!(
+            triangles
+                .iter()
+                .all(|t| t.iter().all(|&i| (i as usize) < vertex_count)),
+            "Mesh index out of range"
+        );
+        if let Some(normals) = &buffers.normals {
+            assert_eq!(normals.len(), vertex_count);
+        }
+        if let Some(uvs) = &buffers.uvs {
+            assert_eq!(uvs.len(), vertex_count);
+        }
+        if let Some(colors) = &buffers.colors {
+            assert_eq!(colors.len(), vertex_count);
+        }
+
+        let bboxes: Vec<AABB> = triangles
+            .iter()
+            .map(|t| triangle_bounding_box(&gather(&buffers.positions, t)))
+            .collect();
+        let light_areas = if material.is_emissive() {
+            triangles
+                .iter()
+                .scan(0.0, |total, t| {
+                    *total += triangle_area(&gather(&buffers.positions, t));
+                    Some(*total)
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        TriangleMesh {
+            buffers,
+            triangles,
+            material,
+            bvh: IndexBvh::new(&bboxes),
+            light_areas,
+        }
+    }
+
+    fn vertices(&self, triangle: &[u32; 3]) -> [Vec3; 3] {
+        gather(&self.buffers.positions, triangle)
+    }
+
+    fn hit_triangle(
+        &self,
+        index: usize,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<HitRecord<'_>> {
+        let triangle = &self.triangles[index];
+        let vertices = self.vertices(triangle);
+        let [a, b, c] = &vertices;
+        let (t, barycentrics) = ray_triangle_intersection(a, b, c, ray, t_min, t_max)?;
+
+        let normals = self
+            .buffers
+            .normals
+            .as_ref()
+            .map(|normals| gather(normals, triangle));
+        let uvs = self.buffers.uvs.as_ref().map(|uvs| gather(uvs, triangle));
+        let mut record = triangle_hit_record(
+            t,
+            ray,
+            &vertices,
+            normals.as_ref(),
+            uvs.as_ref(),
+            &barycentrics,
+            self.material.as_ref(),
+        );
+        record.vertex_color = self.buffers.colors.as_ref().map(|colors| {
+            let [a, b, c] = gather(colors, triangle);
+            a * barycentrics.x + b * barycentrics.y + c * barycentrics.z
+        });
+        Some(record)
+    }
+}
+
+fn gather<T: Copy>(attribute: &[T], triangle: &[u32; 3]) -> [T; 3] {
+    [
+        attribute[triangle[0] as usize],
+        attribute[triangle[1] as usize],
+        attribute[triangle[2] as usize],
+    ]
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.bvh.hit(ray, t_min, t_max, |index, closest_t| {
+            self.hit_triangle(index, ray, t_min, closest_t)
+        })
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        Some(self.bvh.bounding_box())
+    }
+
+    fn is_light(&self) -> bool {
+        self.light_areas.last().is_some_and(|&area| area > 0.0)
+    }
+
+    // Every triangle along the direction could have been picked, not only
+    // the closest, so all of them add to the density.
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        let total_area = *self.light_areas.last().unwrap();
+        let ray = Ray {
+            origin: *origin,
+            direction: *direction,
+            time: 0.0,
+        };
+        let mut density = 0.0;
+        self.bvh.hit(&ray, 0.001, f64::INFINITY, |index, _| {
+            let [a, b, c] = self.vertices(&self.triangles[index]);
+            if let Some((t, _)) = ray_triangle_intersection(&a, &b, &c, &ray, 0.001, f64::INFINITY)
+            {
+                let normal = (b - a).cross(&(c - a));
+                let distance_squared = t * t * direction.norm_squared();
+                let cosine = (direction.dot(&normal) / (direction.norm() * normal.norm())).abs();
+                density += distance_squared / (cosine * total_area);
+            }
+            None
+        });
+        density
+    }
+
+    fn random(&self, origin: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let total_area = *self.light_areas.last().unwrap();
+        let area = Uniform::from(0.0..total_area).sample(rng);
+        let index = self
+            .light_areas
+            .partition_point(|&sum| sum <= area)
+            .min(self.triangles.len() - 1);
+        triangle_random_point(&self.vertices(&self.triangles[index]), rng) - origin
+    }
+
+    fn power(&self) -> f64 {
+        let bbox = self.bvh.bounding_box();
+        let center = (bbox.min + bbox.max) / 2.0;
+        let area = self.light_areas.last().copied().unwrap_or(0.0);
+        area * emitted_luminance(self.material.as_ref(), 0.5, 0.5, &center)
+    }
+}
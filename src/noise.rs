@@ -29,8 +29,8 @@ impl Perlin {
 
     fn generate_permutations(rng: &mut impl RngCore) -> [usize; POINT_COUNT] {
         let mut base = [0; POINT_COUNT];
-        for i in 0..base.len() {
-            base[i] = i;
+        for (i, value) in base.iter_mut().enumerate() {
+            *value = i;
         }
         base.shuffle(rng);
         base
@@ -53,9 +53,9 @@ impl Perlin {
         let ww = w.powi(2) * (3.0 - 2.0 * w);
 
         let mut acc = 0.0;
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
+        for (i, plane) in c.iter().enumerate() {
+            for (j, row) in plane.iter().enumerate() {
+                for (k, gradient) in row.iter().enumerate() {
                     let fi = i as f64;
                     let fj = j as f64;
                     let fk = k as f64;
@@ -63,7 +63,7 @@ impl Perlin {
                     acc += (fi * uu + (1.0 - fi) * (1.0 - uu))
                         * (fj * vv + (1.0 - fj) * (1.0 - vv))
                         * (fk * ww + (1.0 - fk) * (1.0 - ww))
-                        * c[i][j][k].dot(&weight_v)
+                        * gradient.dot(&weight_v)
                 }
             }
         }
@@ -82,16 +82,15 @@ impl Perlin {
         // Chief kiss
         let mut vals: [[[Vec3; 2]; 2]; 2] = [[[Vec3::zeros(); 2]; 2]; 2];
 
-        for di in 0..2 {
-            for dj in 0..2 {
-                for dk in 0..2 {
+        for (di, plane) in vals.iter_mut().enumerate() {
+            for (dj, row) in plane.iter_mut().enumerate() {
+                for (dk, value) in row.iter_mut().enumerate() {
                     let (x, y, z) = (
-                        i.wrapping_add(di) as usize % POINT_COUNT,
-                        j.wrapping_add(dj) as usize % POINT_COUNT,
-                        k.wrapping_add(dk) as usize % POINT_COUNT,
+                        i.wrapping_add(di) % POINT_COUNT,
+                        j.wrapping_add(dj) % POINT_COUNT,
+                        k.wrapping_add(dk) % POINT_COUNT,
                     );
-                    // TODO range variables into usize
-                    vals[di as usize][dj as usize][dk as usize] = self.random_vectors
+                    *value = self.random_vectors
                         [self.permutations_x[x] ^ self.permutations_y[y] ^ self.permutations_z[z]];
                 }
             }
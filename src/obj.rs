@@ -0,0 +1,567 @@
+// Wavefront OBJ/MTL import.
+//
+// Every group (`g` or `o`) and material (`usemtl`) change starts a new
+// TriangleMesh, all of them sharing the same vertex buffers. Polygons are
+// triangulated as fans. MTL materials are mapped on the closest material this
+// renderer has:
+// - an emissive color (`Ke`) gives a DiffuseLight,
+// - transparency (`d` < 1, `Tr` > 0 or `illum` 4, 6, 7) gives a Dielectric
+//   with `Ni` as its IOR,
+// - mirror illumination models (`illum` 3, 5) give a Metal colored by `Ks`,
+//   its roughness derived from `Ns`,
+// - everything else is Lambertian, with `map_Kd` as its texture if present.
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::{MeshBuffers, TriangleMesh};
+use crate::texture::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, std::io::Error),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io(path, err) => write!(f, "couldn't read {} ({})", path.display(), err),
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}, line {}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+// Loads all the meshes of an OBJ file. When material_override is given, the
+// MTL files aren't read at all.
+pub fn load_obj(
+    path: &Path,
+    material_override: Option<Arc<dyn Material>>,
+) -> Result<Vec<TriangleMesh>, ObjError> {
+    let source =
+        std::fs::read_to_string(path).map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+    parse_obj(path, &source, material_override)
+}
+
+// MTL files are found next to path, which also names the file in errors.
+fn parse_obj(
+    path: &Path,
+    source: &str,
+    material_override: Option<Arc<dyn Material>>,
+) -> Result<Vec<TriangleMesh>, ObjError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let error = |line: usize, message: String| ObjError::Parse {
+        path: path.to_path_buf(),
+        line,
+        message,
+    };
+
+    let mut positions: Vec<Vec3> = vec![];
+    let mut normals: Vec<Vec3> = vec![];
+    let mut uvs: Vec<Vec2> = vec![];
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let default_material: Arc<dyn Material> = match &material_override {
+        Some(material) => material.clone(),
+        None => Arc::new(Lambertian {
+            albedo: Arc::new(SolidColor::new(0.73, 0.73, 0.73)),
+        }),
+    };
+
+    let mut groups = vec![FaceGroup {
+        material: default_material,
+        faces: vec![],
+    }];
+
+    for (line_index, line) in source.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => positions.push(parse_vec3(&args).map_err(|m| error(line_number, m))?),
+            "vn" => normals.push(parse_vec3(&args).map_err(|m| error(line_number, m))?),
+            "vt" => {
+                let numbers = parse_numbers(&args, 1).map_err(|m| error(line_number, m))?;
+                uvs.push(Vec2::new(numbers[0], *numbers.get(1).unwrap_or(&0.0)));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(
+                        line_number,
+                        format!("a face needs at least 3 vertices, got {}", args.len()),
+                    ));
+                }
+                let face = args
+                    .iter()
+                    .map(|corner| {
+                        parse_corner(corner, positions.len(), uvs.len(), normals.len())
+                            .map_err(|m| error(line_number, m))
+                    })
+                    .collect::<Result<Vec<Corner>, ObjError>>()?;
+                groups.last_mut().unwrap().faces.push(face);
+            }
+            "g" | "o" => {
+                let material = groups.last().unwrap().material.clone();
+                start_group(&mut groups, material);
+            }
+            "usemtl" => {
+                if material_override.is_some() {
+                    continue;
+                }
+                let name = args.join(" ");
+                let material = match materials.get(&name) {
+                    Some(material) => material.clone(),
+                    None => return Err(error(line_number, format!("unknown material `{}`", name))),
+                };
+                start_group(&mut groups, material);
+            }
+            "mtllib" => {
+                if material_override.is_some() {
+                    continue;
+                }
+                if args.is_empty() {
+                    return Err(error(line_number, "`mtllib` needs a file name".to_string()));
+                }
+                // File names can have spaces, but so can the list of files.
+                let full_name = base_dir.join(args.join(" "));
+                let files: Vec<PathBuf> = if full_name.exists() {
+                    vec![full_name]
+                } else {
+                    args.iter().map(|file| base_dir.join(file)).collect()
+                };
+                for file in files {
+                    materials.extend(load_mtl(&file)?);
+                }
+            }
+            // Smoothing groups, lines, points, curves...
+            _ => {}
+        }
+    }
+
+    build_meshes(positions, uvs, normals, groups)
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+struct FaceGroup {
+    material: Arc<dyn Material>,
+    faces: Vec<Vec<Corner>>,
+}
+
+fn start_group(groups: &mut Vec<FaceGroup>, material: Arc<dyn Material>) {
+    if groups.last().unwrap().faces.is_empty() {
+        groups.pop();
+    }
+    groups.push(FaceGroup {
+        material,
+        faces: vec![],
+    });
+}
+
+fn parse_numbers(args: &[&str], min_count: usize) -> Result<Vec<f64>, String> {
+    if args.len() < min_count {
+        return Err(format!(
+            "expected at least {} numbers, got {}",
+            min_count,
+            args.len()
+        ));
+    }
+    args.iter()
+        .map(|arg| {
+            arg.parse::<f64>()
+                .map_err(|_| format!("invalid number `{}`", arg))
+        })
+        .collect()
+}
+
+fn parse_vec3(args: &[&str]) -> Result<Vec3, String> {
+    let numbers = parse_numbers(args, 3)?;
+    Ok(Vec3::new(numbers[0], numbers[1], numbers[2]))
+}
+
+// OBJ indices start at 1, negative ones are relative to the end of the list.
+fn resolve_index(index: &str, count: usize, what: &str) -> Result<usize, String> {
+    let value: i64 = index
+        .parse()
+        .map_err(|_| format!("invalid {} index `{}`", what, index))?;
+    let resolved = if value < 0 {
+        count as i64 + value
+    } else {
+        value - 1
+    };
+    if value == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("{} index {} is out of range", what, value));
+    }
+    Ok(resolved as usize)
+}
+
+// v, v/vt, v//vn or v/vt/vn
+fn parse_corner(
+    corner: &str,
+    position_count: usize,
+    uv_count: usize,
+    normal_count: usize,
+) -> Result<Corner, String> {
+    let parts: Vec<&str> = corner.split('/').collect();
+    if parts.len() > 3 {
+        return Err(format!("malformed face vertex `{}`", corner));
+    }
+    let position = resolve_index(parts[0], position_count, "vertex")?;
+    let uv = match parts.get(1) {
+        Some(uv) if !uv.is_empty() => Some(resolve_index(uv, uv_count, "texture coordinate")?),
+        _ => None,
+    };
+    let normal = match parts.get(2) {
+        Some(normal) if !normal.is_empty() => Some(resolve_index(normal, normal_count, "normal")?),
+        Some(_) => return Err(format!("malformed face vertex `{}`", corner)),
+        None => None,
+    };
+    Ok(Corner {
+        position,
+        uv,
+        normal,
+    })
+}
+
+// OBJ indexes every attribute separately, the meshes want a single index per
+// vertex, so each distinct combination becomes a vertex.
+fn build_meshes(
+    positions: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    normals: Vec<Vec3>,
+    groups: Vec<FaceGroup>,
+) -> Result<Vec<TriangleMesh>, ObjError> {
+    let faces = || groups.iter().flat_map(|group| group.faces.iter()).flatten();
+    let has_uvs = faces().any(|corner| corner.uv.is_some());
+    let has_normals = faces().any(|corner| corner.normal.is_some());
+
+    let mut buffers = MeshBuffers {
+        positions: vec![],
+        normals: if has_normals { Some(vec![]) } else { None },
+        uvs: if has_uvs { Some(vec![]) } else { None },
+        colors: None,
+    };
+    let mut vertex_map: HashMap<Corner, u32> = HashMap::new();
+    let mut group_triangles = vec![];
+
+    for group in &groups {
+        let mut triangles: Vec<[u32; 3]> = vec![];
+        for face in &group.faces {
+            // Faces without normals in a mesh that has some get their own
+            // vertices with the face normal.
+            let face_normal = (positions[face[1].position] - positions[face[0].position])
+                .cross(&(positions[face[2].position] - positions[face[0].position]))
+                .normalize();
+
+            let mut indices = vec![];
+            for corner in face {
+                let shared = corner.normal.is_some() || !has_normals;
+                if let (true, Some(&index)) = (shared, vertex_map.get(corner)) {
+                    indices.push(index);
+                    continue;
+                }
+
+                let index = buffers.positions.len() as u32;
+                buffers.positions.push(positions[corner.position]);
+                if let Some(buffer) = buffers.uvs.as_mut() {
+                    buffer.push(corner.uv.map_or(Vec2::zeros(), |uv| uvs[uv]));
+                }
+                if let Some(buffer) = buffers.normals.as_mut() {
+                    buffer.push(
+                        corner
+                            .normal
+                            .map_or(face_normal, |n| normals[n].normalize()),
+                    );
+                }
+                if shared {
+                    vertex_map.insert(*corner, index);
+                }
+                indices.push(index);
+            }
+
+            for i in 1..indices.len() - 1 {
+                triangles.push([indices[0], indices[i], indices[i + 1]]);
+            }
+        }
+        group_triangles.push(triangles);
+    }
+
+    let buffers = Arc::new(buffers);
+    Ok(groups
+        .into_iter()
+        .zip(group_triangles)
+        .filter(|(_, triangles)| !triangles.is_empty())
+        .map(|(group, triangles)| TriangleMesh::new(buffers.clone(), triangles, group.material))
+        .collect())
+}
+
+#[derive(Default)]
+struct MtlDescription {
+    diffuse: Option<Vec3>,
+    diffuse_map: Option<PathBuf>,
+    specular: Option<Vec3>,
+    emissive: Option<Vec3>,
+    shininess: Option<f64>,
+    ior: Option<f64>,
+    dissolve: Option<f64>,
+    illum: Option<u32>,
+}
+
+impl MtlDescription {
+    fn build(&self, path: &Path, line: usize) -> Result<Arc<dyn Material>, ObjError> {
+        if let Some(emissive) = self.emissive {
+            if emissive.max() > 0.0 {
+                return Ok(Arc::new(DiffuseLight {
+                    emissive: Arc::new(SolidColor { albedo: emissive }),
+                }));
+            }
+        }
+
+        let transparent = self.dissolve.is_some_and(|d| d < 1.0)
+            || matches!(self.illum, Some(4) | Some(6) | Some(7));
+        if transparent {
+            return Ok(Arc::new(Dielectric {
+                ior: self.ior.unwrap_or(1.5),
+            }));
+        }
+
+        if let Some(3) | Some(5) = self.illum {
+            // Phong exponent to roughness, sharp highlights are smooth metals.
+            let roughness = (2.0 / (self.shininess.unwrap_or(0.0) + 2.0)).sqrt();
+            return Ok(Arc::new(Metal {
+                albedo: Arc::new(SolidColor {
+                    albedo: self.specular.unwrap_or_else(|| Vec3::from_element(1.0)),
+                }),
+                roughness,
+            }));
+        }
+
+        let albedo: Arc<dyn Texture> = match &self.diffuse_map {
+            Some(map) => match ImageTexture::open(&map.to_string_lossy()) {
+                Ok(texture) => Arc::new(texture),
+                Err(err) => {
+                    return Err(ObjError::Parse {
+                        path: path.to_path_buf(),
+                        line,
+                        message: format!(
+                            "couldn't open the picture at {} ({})",
+                            map.display(),
+                            err
+                        ),
+                    })
+                }
+            },
+            None => Arc::new(SolidColor {
+                albedo: self.diffuse.unwrap_or_else(|| Vec3::from_element(0.73)),
+            }),
+        };
+        Ok(Arc::new(Lambertian { albedo }))
+    }
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, Arc<dyn Material>>, ObjError> {
+    let source =
+        std::fs::read_to_string(path).map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let error = |line: usize, message: String| ObjError::Parse {
+        path: path.to_path_buf(),
+        line,
+        message,
+    };
+
+    let mut materials = HashMap::new();
+    // Name, line of the newmtl and description of the material being read.
+    let mut current: Option<(String, usize, MtlDescription)> = None;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            if let Some((name, line, description)) = current.take() {
+                materials.insert(name, description.build(path, line)?);
+            }
+            current = Some((args.join(" "), line_number, MtlDescription::default()));
+            continue;
+        }
+
+        let description = match current.as_mut() {
+            Some((_, _, description)) => description,
+            None => {
+                return Err(error(
+                    line_number,
+                    format!("`{}` found before any `newmtl`", keyword),
+                ))
+            }
+        };
+        let number = |args: &[&str]| -> Result<f64, ObjError> {
+            parse_numbers(args, 1)
+                .map(|numbers| numbers[0])
+                .map_err(|m| error(line_number, m))
+        };
+        match keyword {
+            "Kd" => {
+                description.diffuse = Some(parse_vec3(&args).map_err(|m| error(line_number, m))?)
+            }
+            "Ks" => {
+                description.specular = Some(parse_vec3(&args).map_err(|m| error(line_number, m))?)
+            }
+            "Ke" => {
+                description.emissive = Some(parse_vec3(&args).map_err(|m| error(line_number, m))?)
+            }
+            "Ns" => description.shininess = Some(number(&args)?),
+            "Ni" => description.ior = Some(number(&args)?),
+            "d" => description.dissolve = Some(number(&args)?),
+            "Tr" => description.dissolve = Some(1.0 - number(&args)?),
+            "illum" => description.illum = Some(number(&args)? as u32),
+            "map_Kd" => {
+                // Options come first, the file name is last.
+                match args.last() {
+                    Some(file) => description.diffuse_map = Some(base_dir.join(file)),
+                    None => {
+                        return Err(error(line_number, "`map_Kd` needs a file name".to_string()))
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some((name, line, description)) = current.take() {
+        materials.insert(name, description.build(path, line)?);
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::Hittable;
+    use crate::Ray;
+
+    fn load(source: &str) -> Result<Vec<TriangleMesh>, ObjError> {
+        parse_obj(Path::new("test.obj"), source, None)
+    }
+
+    fn parse_error(source: &str) -> (usize, String) {
+        match load(source) {
+            Err(ObjError::Parse { line, message, .. }) => (line, message),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("the file loaded"),
+        }
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn loads_polygons_with_their_attributes() {
+        let source = format!(
+            "{}vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n\
+             f 1/1/1 2/2/1 3/3/1 4/4/1 # a quad\n",
+            SQUARE
+        );
+        let meshes = load(&source).unwrap();
+        assert_eq!(meshes.len(), 1);
+        let bbox = meshes[0].bounding_box(0.0, 1.0).unwrap();
+        assert!(bbox.min.x < 0.0 && bbox.max.x > 1.0 && bbox.max.y > 1.0);
+
+        // Both triangles of the fan are there, with the UVs interpolated.
+        for (x, y) in [(0.75, 0.25), (0.25, 0.75)] {
+            let ray = Ray {
+                origin: Vec3::new(x, y, 1.0),
+                direction: Vec3::new(0.0, 0.0, -1.0),
+                time: 0.0,
+            };
+            let record = meshes[0].hit(&ray, 0.001, f64::INFINITY).unwrap();
+            assert!((record.u - x).abs() < 1e-9 && (record.v - y).abs() < 1e-9);
+            assert!((record.normal - Vec3::new(0.0, 0.0, 1.0)).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn groups_become_meshes() {
+        let source = format!(
+            "{}g first\nf 1 2 3\ng second\nf -4 -2 -1\ng empty\n",
+            SQUARE
+        );
+        assert_eq!(load(&source).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let cases = [
+            ("v 1 x 2\n", 1, "invalid number `x`"),
+            ("v 1 2\n", 1, "expected at least 3 numbers, got 2"),
+            ("f 1 2 3\n", 1, "vertex index 1 is out of range"),
+            (
+                &format!("{}f 1 2\n", SQUARE),
+                5,
+                "a face needs at least 3 vertices, got 2",
+            ),
+            (
+                &format!("{}f 1 2 0\n", SQUARE),
+                5,
+                "vertex index 0 is out of range",
+            ),
+            (
+                &format!("{}f 1/1 2 3\n", SQUARE),
+                5,
+                "texture coordinate index 1 is out of range",
+            ),
+            (
+                &format!("{}f 1/1/1/1 2 3\n", SQUARE),
+                5,
+                "malformed face vertex `1/1/1/1`",
+            ),
+            (
+                &format!("{}f 1// 2 3\n", SQUARE),
+                5,
+                "malformed face vertex `1//`",
+            ),
+            ("usemtl nope\n", 1, "unknown material `nope`"),
+            ("mtllib\n", 1, "`mtllib` needs a file name"),
+        ];
+        for (source, line, message) in &cases {
+            assert_eq!(parse_error(source), (*line, message.to_string()));
+        }
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let path = Path::new("missing/model.obj");
+        assert!(matches!(load_obj(path, None), Err(ObjError::Io(..))));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::environment::Environment;
+use crate::hittable::Hittable;
+use crate::math::*;
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform, UnitSphere};
+use std::f64::consts::{PI, TAU};
+
+// A distribution of directions, as in The Rest of Your Life: generate picks a
+// direction and value gives the density, by solid angle, of picking it.
+pub trait Pdf {
+    fn value(&self, direction: &Vec3) -> f64;
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3;
+}
+
+impl<P: Pdf + ?Sized> Pdf for &P {
+    fn value(&self, direction: &Vec3) -> f64 {
+        (**self).value(direction)
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        (**self).generate(rng)
+    }
+}
+
+// Directions around a normal, weighted by their cosine to it, for Lambertian
+// surfaces.
+pub struct CosinePdf {
+    normal: Vec3,
+    tangent: Vec3,
+    bitangent: Vec3,
+}
+
+impl CosinePdf {
+    pub fn new(normal: &Vec3) -> CosinePdf {
+        let normal = normal.normalize();
+        let (tangent, bitangent) = orthonormal_basis(&normal);
+        CosinePdf {
+            normal,
+            tangent,
+            bitangent,
+        }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: &Vec3) -> f64 {
+        let cosine = self.normal.dot(&direction.normalize());
+        cosine.max(0.0) / PI
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let r1: f64 = distribution.sample(rng);
+        let r2: f64 = distribution.sample(rng);
+        let phi = TAU * r1;
+        let radius = r2.sqrt();
+        self.tangent * (phi.cos() * radius)
+            + self.bitangent * (phi.sin() * radius)
+            + self.normal * (1.0 - r2).sqrt()
+    }
+}
+
+// Every direction equally, for isotropic volumes.
+pub struct SpherePdf;
+
+impl Pdf for SpherePdf {
+    fn value(&self, _direction: &Vec3) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let v: [f64; 3] = UnitSphere.sample(rng);
+        Vec3::new(v[0], v[1], v[2])
+    }
+}
+
+// The Henyey-Greenstein phase function around the incoming direction,
+// favoring forward scattering for a positive anisotropy.
+pub struct HenyeyGreensteinPdf {
+    forward: Vec3,
+    anisotropy: f64,
+}
+
+impl HenyeyGreensteinPdf {
+    pub fn new(incoming: &Vec3, anisotropy: f64) -> HenyeyGreensteinPdf {
+        HenyeyGreensteinPdf {
+            forward: incoming.normalize(),
+            anisotropy,
+        }
+    }
+}
+
+impl Pdf for HenyeyGreensteinPdf {
+    fn value(&self, direction: &Vec3) -> f64 {
+        let g = self.anisotropy;
+        let cos_theta = self.forward.dot(&direction.normalize());
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let g = self.anisotropy;
+        let xi: f64 = distribution.sample(rng);
+        // Cosine of the angle between the incoming and outgoing directions,
+        // sampled by inverting the phase function's CDF.
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * xi
+        } else {
+            let term = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+            ((1.0 + g * g - term * term) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = TAU * distribution.sample(rng);
+
+        let (tangent, bitangent) = orthonormal_basis(&self.forward);
+        self.forward * cos_theta
+            + tangent * (sin_theta * phi.cos())
+            + bitangent * (sin_theta * phi.sin())
+    }
+}
+
+// Metal's rough reflections: the mirrored direction offset by a random point
+// of a sphere of radius roughness. A direction's density is found from where
+// it crosses that sphere.
+pub struct FuzzyReflectionPdf {
+    reflected: Vec3,
+    roughness: f64,
+}
+
+impl FuzzyReflectionPdf {
+    pub fn new(reflected: &Vec3, roughness: f64) -> FuzzyReflectionPdf {
+        FuzzyReflectionPdf {
+            reflected: reflected.normalize(),
+            roughness,
+        }
+    }
+}
+
+impl Pdf for FuzzyReflectionPdf {
+    fn value(&self, direction: &Vec3) -> f64 {
+        let direction = direction.normalize();
+        let half_b = direction.dot(&self.reflected);
+        let c = 1.0 - self.roughness * self.roughness;
+        let discriminant = half_b * half_b - c;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let sqrtd = discriminant.sqrt();
+        // The sphere's points are uniformly distributed, so each crossing adds
+        // its area density, projected to a solid angle one.
+        let area_density = 1.0 / (4.0 * PI * self.roughness * self.roughness);
+        [half_b - sqrtd, half_b + sqrtd]
+            .iter()
+            .filter(|&&t| t > 0.0)
+            .map(|&t| {
+                let normal = (direction * t - self.reflected) / self.roughness;
+                area_density * t * t / direction.dot(&normal).abs()
+            })
+            .sum()
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let v: [f64; 3] = UnitSphere.sample(rng);
+        (self.reflected + self.roughness * Vec3::new(v[0], v[1], v[2])).normalize()
+    }
+}
+
+// Directions from origin towards a hittable, for the lights.
+pub struct HittablePdf<'a> {
+    pub origin: Vec3,
+    pub hittable: &'a dyn Hittable,
+}
+
+impl Pdf for HittablePdf<'_> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        self.hittable.pdf_value(&self.origin, direction)
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        self.hittable.random(&self.origin, rng)
+    }
+}
+
+// Directions towards the environment, from anywhere.
+pub struct EnvironmentPdf<'a> {
+    pub environment: &'a dyn Environment,
+}
+
+impl Pdf for EnvironmentPdf<'_> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        self.environment.pdf_value(direction)
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        self.environment.random(rng)
+    }
+}
+
+// Picks one of its distributions with the probability it's given, the
+// probabilities adding up to one.
+pub struct MixturePdf<P: Pdf> {
+    components: Vec<(f64, P)>,
+}
+
+impl<P: Pdf> MixturePdf<P> {
+    pub fn new(components: Vec<(f64, P)>) -> MixturePdf<P> {
+        assert!(!components.is_empty(), "A mixture needs a distribution");
+        MixturePdf { components }
+    }
+}
+
+impl<P: Pdf> Pdf for MixturePdf<P> {
+    fn value(&self, direction: &Vec3) -> f64 {
+        // Distributions that are never picked are skipped, as finding their
+        // density can mean casting a ray.
+        self.components
+            .iter()
+            .filter(|(weight, _)| *weight > 0.0)
+            .map(|(weight, pdf)| weight * pdf.value(direction))
+            .sum()
+    }
+
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let mut remaining: f64 = Uniform::from(0.0..1.0).sample(rng);
+        for (weight, pdf) in &self.components {
+            if remaining < *weight {
+                return pdf.generate(rng);
+            }
+            remaining -= weight;
+        }
+        // Rounding errors can leave a bit of probability past the last one.
+        self.components[self.components.len() - 1].1.generate(rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    // Integrates the pdf over the sphere from uniform samples, and checks the
+    // directions it generates have a density.
+    fn assert_normalized(pdf: &dyn Pdf) {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let count = 200000;
+        let sum: f64 = (0..count)
+            .map(|_| pdf.value(&SpherePdf.generate(&mut rng)))
+            .sum();
+        let integral = sum * 4.0 * PI / count as f64;
+        assert!((integral - 1.0).abs() < 0.02, "integral {}", integral);
+        for _ in 0..1000 {
+            let direction = pdf.generate(&mut rng);
+            assert!(pdf.value(&direction) > 0.0, "{:?}", direction);
+        }
+    }
+
+    #[test]
+    fn pdfs_integrate_to_one() {
+        let normal = Vec3::new(1.0, 2.0, -0.5);
+        assert_normalized(&CosinePdf::new(&normal));
+        assert_normalized(&SpherePdf);
+        assert_normalized(&HenyeyGreensteinPdf::new(&normal, 0.0));
+        assert_normalized(&HenyeyGreensteinPdf::new(&normal, 0.5));
+        assert_normalized(&HenyeyGreensteinPdf::new(&normal, -0.3));
+        assert_normalized(&FuzzyReflectionPdf::new(&normal, 0.5));
+    }
+
+    #[test]
+    fn cosine_directions_stay_above_the_surface() {
+        let normal = Vec3::new(0.0, 0.0, 3.0);
+        let pdf = CosinePdf::new(&normal);
+        let mut rng = SmallRng::seed_from_u64(2);
+        assert!((0..1000).all(|_| pdf.generate(&mut rng).z >= 0.0));
+        assert_eq!(pdf.value(&-normal), 0.0);
+        assert!((pdf.value(&normal) - 1.0 / PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mixtures_pick_distributions_by_weight() {
+        let up = CosinePdf::new(&Vec3::new(0.0, 0.0, 1.0));
+        let down = CosinePdf::new(&Vec3::new(0.0, 0.0, -1.0));
+        let mixture = MixturePdf::new(vec![(0.25, &up), (0.75, &down)]);
+        assert_normalized(&mixture);
+        let direction = Vec3::new(0.0, 0.0, 1.0);
+        assert!((mixture.value(&direction) - 0.25 / PI).abs() < 1e-12);
+
+        let mut rng = SmallRng::seed_from_u64(3);
+        let count = 10000;
+        let ups = (0..count)
+            .filter(|_| mixture.generate(&mut rng).z > 0.0)
+            .count();
+        assert!((ups as f64 / count as f64 - 0.25).abs() < 0.02);
+    }
+}
@@ -0,0 +1,657 @@
+// Stanford PLY import, ASCII and binary (both endiannesses).
+//
+// Files with faces give a TriangleMesh, polygons being triangulated as fans.
+// Files with only vertices give a PointCloud, sized by the vertices' `radius`
+// property when present. Vertex colors (`red`, `green`, `blue`) tint a white
+// Lambertian material. Integer colors are read like image pixels, floating
+// point ones as linear values.
+//
+// Recognized vertex properties are `x y z`, `nx ny nz`, `u v` (or `s t`,
+// `texture_u texture_v`), the colors and `radius`. Other properties and
+// elements are skipped.
+use crate::hittable::Hittable;
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::{MeshBuffers, TriangleMesh};
+use crate::point_cloud::PointCloud;
+use crate::texture::*;
+use std::convert::TryInto;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum PlyError {
+    Io(PathBuf, std::io::Error),
+    Parse { path: PathBuf, message: String },
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlyError::Io(path, err) => write!(f, "couldn't read {} ({})", path.display(), err),
+            PlyError::Parse { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for PlyError {}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Option<ScalarType> {
+        Some(match name {
+            "char" | "int8" => ScalarType::Int8,
+            "uchar" | "uint8" => ScalarType::UInt8,
+            "short" | "int16" => ScalarType::Int16,
+            "ushort" | "uint16" => ScalarType::UInt16,
+            "int" | "int32" => ScalarType::Int32,
+            "uint" | "uint32" => ScalarType::UInt32,
+            "float" | "float32" => ScalarType::Float32,
+            "double" | "float64" => ScalarType::Float64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Float64 => 8,
+        }
+    }
+
+    // Scale bringing integer colors in [0, 1], None for floating point types.
+    fn color_scale(self) -> Option<f64> {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => Some(255.0),
+            ScalarType::Int16 | ScalarType::UInt16 => Some(65535.0),
+            ScalarType::Int32 | ScalarType::UInt32 => Some(u32::MAX as f64),
+            ScalarType::Float32 | ScalarType::Float64 => None,
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    scalar: ScalarType,
+    // Type of the item count for list properties.
+    list_count: Option<ScalarType>,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| p.list_count.is_none() && names.contains(&p.name.as_str()))
+    }
+}
+
+// Reads the body's values one by one, whatever the format.
+struct BodyReader<'a> {
+    format: Format,
+    data: &'a [u8],
+    position: usize,
+    tokens: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> BodyReader<'a> {
+    fn read(&mut self, scalar: ScalarType) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            let token = self
+                .tokens
+                .next()
+                .ok_or_else(|| "unexpected end of file".to_string())?;
+            return token
+                .parse::<f64>()
+                .map_err(|_| format!("invalid number `{}`", token));
+        }
+
+        let size = scalar.size();
+        let bytes = self
+            .data
+            .get(self.position..self.position + size)
+            .ok_or_else(|| "unexpected end of file".to_string())?;
+        self.position += size;
+        let mut buffer = [0u8; 8];
+        buffer[..size].copy_from_slice(bytes);
+        if self.format == Format::BinaryBigEndian {
+            buffer[..size].reverse();
+        }
+        let value = match scalar {
+            ScalarType::Int8 => buffer[0] as i8 as f64,
+            ScalarType::UInt8 => buffer[0] as f64,
+            ScalarType::Int16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            ScalarType::UInt16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            ScalarType::Int32 => i32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            ScalarType::UInt32 => u32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            ScalarType::Float32 => f32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            ScalarType::Float64 => f64::from_le_bytes(buffer),
+        };
+        Ok(value)
+    }
+}
+
+// Loads a PLY file as a mesh or a point cloud. When material_override is
+// given, vertex colors are ignored. point_radius is used for points without
+// a `radius` property.
+pub fn load_ply(
+    path: &Path,
+    material_override: Option<Arc<dyn Material>>,
+    point_radius: f64,
+) -> Result<Arc<dyn Hittable>, PlyError> {
+    let data = std::fs::read(path).map_err(|err| PlyError::Io(path.to_path_buf(), err))?;
+    parse_ply(path, &data, material_override, point_radius)
+}
+
+// The path only names the file in errors.
+fn parse_ply(
+    path: &Path,
+    data: &[u8],
+    material_override: Option<Arc<dyn Material>>,
+    point_radius: f64,
+) -> Result<Arc<dyn Hittable>, PlyError> {
+    let error = |message: String| PlyError::Parse {
+        path: path.to_path_buf(),
+        message,
+    };
+
+    let (format, elements, body_start) = parse_header(data).map_err(error)?;
+    let tokens = match format {
+        Format::Ascii => std::str::from_utf8(&data[body_start..])
+            .map_err(|_| error("the ASCII body isn't valid text".to_string()))?
+            .split_ascii_whitespace(),
+        _ => "".split_ascii_whitespace(),
+    };
+    let mut reader = BodyReader {
+        format,
+        data,
+        position: body_start,
+        tokens,
+    };
+
+    let mut positions: Vec<Vec3> = vec![];
+    let mut normals: Vec<Vec3> = vec![];
+    let mut uvs: Vec<Vec2> = vec![];
+    let mut colors: Vec<Vec3> = vec![];
+    let mut radii: Vec<f64> = vec![];
+    let mut faces: Vec<[u32; 3]> = vec![];
+    let mut has_faces = false;
+
+    for element in &elements {
+        let result = match element.name.as_str() {
+            "vertex" => read_vertices(
+                &mut reader,
+                element,
+                point_radius,
+                (&mut positions, &mut normals, &mut uvs),
+                (&mut colors, &mut radii),
+            ),
+            "face" => {
+                has_faces = element.count > 0;
+                read_faces(&mut reader, element, &mut faces)
+            }
+            _ => skip_element(&mut reader, element),
+        };
+        result.map_err(|message| error(format!("element `{}`: {}", element.name, message)))?;
+    }
+
+    if positions.is_empty() {
+        return Err(error("the file has no vertices".to_string()));
+    }
+    if let Some(index) = faces
+        .iter()
+        .flatten()
+        .find(|&&i| i as usize >= positions.len())
+    {
+        return Err(error(format!("vertex index {} out of range", index)));
+    }
+
+    let colors = (!colors.is_empty() && material_override.is_none()).then_some(colors);
+    let material: Arc<dyn Material> = match (material_override, &colors) {
+        (Some(material), _) => material,
+        (None, Some(_)) => Arc::new(Lambertian {
+            albedo: Arc::new(SolidColor::new(1.0, 1.0, 1.0)),
+        }),
+        (None, None) => Arc::new(Lambertian {
+            albedo: Arc::new(SolidColor::new(0.73, 0.73, 0.73)),
+        }),
+    };
+
+    if !has_faces {
+        if radii
+            .iter()
+            .any(|&radius| !(radius > 0.0 && radius.is_finite()))
+        {
+            return Err(error("point radii must be positive and finite".to_string()));
+        }
+        return Ok(Arc::new(PointCloud::new(
+            positions, radii, colors, material,
+        )));
+    }
+    if faces.is_empty() {
+        return Err(error("the file has no triangles".to_string()));
+    }
+
+    let vertex_count = positions.len();
+    let buffers = Arc::new(MeshBuffers {
+        positions,
+        normals: (normals.len() == vertex_count).then_some(normals),
+        uvs: (uvs.len() == vertex_count).then_some(uvs),
+        colors,
+    });
+    Ok(Arc::new(TriangleMesh::new(buffers, faces, material)))
+}
+
+fn parse_header(data: &[u8]) -> Result<(Format, Vec<Element>, usize), String> {
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    let mut position = 0;
+
+    loop {
+        let line_end = match data[position..].iter().position(|&b| b == b'\n') {
+            Some(offset) => position + offset,
+            None => return Err("the header has no `end_header`".to_string()),
+        };
+        let line = std::str::from_utf8(&data[position..line_end])
+            .map_err(|_| "the header isn't valid text".to_string())?
+            .trim();
+        let first_line = position == 0;
+        position = line_end + 1;
+
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        if first_line {
+            if words != ["ply"] {
+                return Err("not a PLY file".to_string());
+            }
+            continue;
+        }
+        match words.as_slice() {
+            ["format", name, _version] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(format!("unknown format `{}`", name)),
+                });
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("invalid element count `{}`", count))?,
+                properties: vec![],
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let property = Property {
+                    name: name.to_string(),
+                    scalar: parse_type(item_type)?,
+                    list_count: Some(parse_type(count_type)?),
+                };
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => return Err("property outside of an element".to_string()),
+                }
+            }
+            ["property", scalar, name] => {
+                let property = Property {
+                    name: name.to_string(),
+                    scalar: parse_type(scalar)?,
+                    list_count: None,
+                };
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => return Err("property outside of an element".to_string()),
+                }
+            }
+            ["end_header"] => break,
+            _ => return Err(format!("invalid header line `{}`", line)),
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements, position)),
+        None => Err("the header has no format".to_string()),
+    }
+}
+
+fn parse_type(name: &str) -> Result<ScalarType, String> {
+    ScalarType::parse(name).ok_or_else(|| format!("unknown property type `{}`", name))
+}
+
+// Reads a row of scalar properties, list properties being skipped.
+fn read_row(reader: &mut BodyReader, element: &Element, row: &mut [f64]) -> Result<(), String> {
+    for (value, property) in row.iter_mut().zip(&element.properties) {
+        match property.list_count {
+            Some(count_type) => {
+                let count = reader.read(count_type)? as usize;
+                for _ in 0..count {
+                    reader.read(property.scalar)?;
+                }
+            }
+            None => *value = reader.read(property.scalar)?,
+        }
+    }
+    Ok(())
+}
+
+fn read_vertices(
+    reader: &mut BodyReader,
+    element: &Element,
+    point_radius: f64,
+    (positions, normals, uvs): (&mut Vec<Vec3>, &mut Vec<Vec3>, &mut Vec<Vec2>),
+    (colors, radii): (&mut Vec<Vec3>, &mut Vec<f64>),
+) -> Result<(), String> {
+    let position = [
+        element.find(&["x"]),
+        element.find(&["y"]),
+        element.find(&["z"]),
+    ];
+    let position = match position {
+        [Some(x), Some(y), Some(z)] => [x, y, z],
+        _ => return Err("vertices need x, y and z properties".to_string()),
+    };
+    let normal = match [
+        element.find(&["nx"]),
+        element.find(&["ny"]),
+        element.find(&["nz"]),
+    ] {
+        [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+        _ => None,
+    };
+    let uv = match [
+        element.find(&["u", "s", "texture_u"]),
+        element.find(&["v", "t", "texture_v"]),
+    ] {
+        [Some(u), Some(v)] => Some([u, v]),
+        _ => None,
+    };
+    let color = match [
+        element.find(&["red", "diffuse_red"]),
+        element.find(&["green", "diffuse_green"]),
+        element.find(&["blue", "diffuse_blue"]),
+    ] {
+        [Some(r), Some(g), Some(b)] => Some([r, g, b]),
+        _ => None,
+    };
+    let radius = element.find(&["radius"]);
+
+    let mut row = vec![0.0; element.properties.len()];
+    for _ in 0..element.count {
+        read_row(reader, element, &mut row)?;
+        let gather = |indices: &[usize]| -> Vec<f64> { indices.iter().map(|&i| row[i]).collect() };
+
+        positions.push(Vec3::from_vec(gather(&position)));
+        if let Some(normal) = &normal {
+            normals.push(Vec3::from_vec(gather(normal)));
+        }
+        if let Some(uv) = &uv {
+            uvs.push(Vec2::from_vec(gather(uv)));
+        }
+        if let Some(color) = &color {
+            colors.push(Vec3::from_iterator(color.iter().map(|&i| {
+                match element.properties[i].scalar.color_scale() {
+                    Some(scale) => (row[i] / scale).powf(2.2),
+                    None => row[i],
+                }
+            })));
+        }
+        radii.push(radius.map_or(point_radius, |i| row[i]));
+    }
+    Ok(())
+}
+
+fn read_faces(
+    reader: &mut BodyReader,
+    element: &Element,
+    faces: &mut Vec<[u32; 3]>,
+) -> Result<(), String> {
+    let indices = element.properties.iter().position(|p| {
+        p.list_count.is_some() && (p.name == "vertex_indices" || p.name == "vertex_index")
+    });
+    let indices = match indices {
+        Some(indices) => indices,
+        None => return Err("faces need a vertex_indices list".to_string()),
+    };
+
+    let mut polygon: Vec<u32> = vec![];
+    for _ in 0..element.count {
+        for (index, property) in element.properties.iter().enumerate() {
+            let count = match property.list_count {
+                Some(count_type) => reader.read(count_type)? as usize,
+                None => 1,
+            };
+            polygon.clear();
+            for _ in 0..count {
+                let value = reader.read(property.scalar)?;
+                if index == indices {
+                    if value < 0.0 || value > u32::MAX as f64 {
+                        return Err(format!("invalid vertex index {}", value));
+                    }
+                    polygon.push(value as u32);
+                }
+            }
+            if index == indices && polygon.len() >= 3 {
+                for i in 1..polygon.len() - 1 {
+                    faces.push([polygon[0], polygon[i], polygon[i + 1]]);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn skip_element(reader: &mut BodyReader, element: &Element) -> Result<(), String> {
+    let mut row = vec![0.0; element.properties.len()];
+    for _ in 0..element.count {
+        read_row(reader, element, &mut row)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ray;
+
+    fn load(data: &[u8]) -> Result<Arc<dyn Hittable>, PlyError> {
+        parse_ply(Path::new("test.ply"), data, None, 0.5)
+    }
+
+    fn parse_error(data: &[u8]) -> String {
+        match load(data) {
+            Err(PlyError::Parse { message, .. }) => message,
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("the file loaded"),
+        }
+    }
+
+    fn header(format: &str, vertices: usize) -> String {
+        format!(
+            "ply\nformat {} 1.0\ncomment a triangle\nelement vertex {}\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format, vertices
+        )
+    }
+
+    // A red triangle in the z = 0 plane.
+    fn binary_triangle(
+        format: &str,
+        to_bytes: fn(f32) -> [u8; 4],
+        index: fn(i32) -> [u8; 4],
+    ) -> Vec<u8> {
+        let mut data = header(format, 3).into_bytes();
+        for (x, y) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)] {
+            for coordinate in [x, y, 0.0] {
+                data.extend_from_slice(&to_bytes(coordinate));
+            }
+            data.extend_from_slice(&[255, 0, 0]);
+        }
+        data.push(3);
+        for i in 0..3 {
+            data.extend_from_slice(&index(i));
+        }
+        data
+    }
+
+    fn hit_center(hittable: &dyn Hittable) -> (Vec3, Option<Vec3>) {
+        let ray = Ray {
+            origin: Vec3::new(0.25, 0.25, 1.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+        };
+        let record = hittable.hit(&ray, 0.001, f64::INFINITY).unwrap();
+        (record.p, record.vertex_color)
+    }
+
+    #[test]
+    fn loads_every_format() {
+        let ascii = format!(
+            "{}0 0 0 255 0 0\n1 0 0 255 0 0\n0 1 0 255 0 0\n3 0 1 2\n",
+            header("ascii", 3)
+        );
+        let files = [
+            ("ascii", ascii.into_bytes()),
+            (
+                "little",
+                binary_triangle("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes),
+            ),
+            (
+                "big",
+                binary_triangle("binary_big_endian", f32::to_be_bytes, i32::to_be_bytes),
+            ),
+        ];
+        for (name, data) in &files {
+            let mesh = load(data).unwrap();
+            let (p, color) = hit_center(mesh.as_ref());
+            assert!((p - Vec3::new(0.25, 0.25, 0.0)).norm() < 1e-6, "{}", name);
+            assert_eq!(color, Some(Vec3::new(1.0, 0.0, 0.0)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn quads_are_split_and_vertices_alone_are_points() {
+        let quad = format!(
+            "{}0 0 0 0 0 0\n1 0 0 0 0 0\n1 1 0 0 0 0\n0 1 0 0 0 0\n4 0 1 2 3\n",
+            header("ascii", 4)
+        );
+        let mesh = load(quad.as_bytes()).unwrap();
+        assert!(hit_center(mesh.as_ref()).0.z.abs() < 1e-9);
+        let ray = Ray {
+            origin: Vec3::new(0.75, 0.75, 1.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+            time: 0.0,
+        };
+        assert!(mesh.hit(&ray, 0.001, f64::INFINITY).is_some());
+
+        let points = "ply\nformat ascii 1.0\nelement vertex 1\n\
+                      property float x\nproperty float y\nproperty float z\nend_header\n0 0 0\n";
+        let cloud = load(points.as_bytes()).unwrap();
+        let bbox = cloud.bounding_box(0.0, 1.0).unwrap();
+        assert!((bbox.max - Vec3::new(0.5, 0.5, 0.5)).norm() < 1e-3);
+    }
+
+    #[test]
+    fn reports_malformed_files() {
+        let triangle = header("ascii", 3);
+        let mut truncated =
+            binary_triangle("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes);
+        truncated.truncate(truncated.len() - 2);
+        let cases: [(&str, Vec<u8>, &str); 9] = [
+            ("magic", b"plx\n".to_vec(), "not a PLY file"),
+            (
+                "unended",
+                b"ply\nformat ascii 1.0\n".to_vec(),
+                "the header has no `end_header`",
+            ),
+            (
+                "format",
+                b"ply\nformat text 1.0\nend_header\n".to_vec(),
+                "unknown format `text`",
+            ),
+            (
+                "property",
+                b"ply\nformat ascii 1.0\nproperty float x\nend_header\n".to_vec(),
+                "property outside of an element",
+            ),
+            (
+                "number",
+                format!("{}0 0 zero 255 0 0\n", triangle).into_bytes(),
+                "element `vertex`: invalid number `zero`",
+            ),
+            (
+                "index",
+                format!(
+                    "{}0 0 0 0 0 0\n1 0 0 0 0 0\n0 1 0 0 0 0\n3 0 1 3\n",
+                    triangle
+                )
+                .into_bytes(),
+                "vertex index 3 out of range",
+            ),
+            (
+                "truncated",
+                truncated,
+                "element `face`: unexpected end of file",
+            ),
+            (
+                "xyz",
+                b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nend_header\n0\n"
+                    .to_vec(),
+                "element `vertex`: vertices need x, y and z properties",
+            ),
+            (
+                "empty",
+                format!("{}0\n", header("ascii", 0).replace("face 1", "face 0")).into_bytes(),
+                "the file has no vertices",
+            ),
+        ];
+        for (name, data, message) in &cases {
+            assert_eq!(parse_error(data), *message, "{}", name);
+        }
+    }
+
+    #[test]
+    fn point_radii_must_be_positive_and_finite() {
+        for radius in ["0", "-1", "nan", "inf"] {
+            let points = format!(
+                "ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\n\
+                 property float z\nproperty float radius\nend_header\n0 0 0 1\n1 0 0 {}\n",
+                radius
+            );
+            assert_eq!(
+                parse_error(points.as_bytes()),
+                "point radii must be positive and finite",
+                "{}",
+                radius
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::bvh::IndexBvh;
+use crate::hittable::*;
+use crate::material::Material;
+use crate::math::*;
+use crate::{HitRecord, Ray};
+use std::sync::Arc;
+
+// Points rendered as small spheres, each with its own radius and optional
+// color, under a single BVH.
+pub struct PointCloud {
+    points: Vec<Vec3>,
+    radii: Vec<f64>,
+    colors: Option<Vec<Vec3>>,
+    material: Arc<dyn Material>,
+    bvh: IndexBvh,
+}
+
+impl PointCloud {
+    pub fn new(
+        points: Vec<Vec3>,
+        radii: Vec<f64>,
+        colors: Option<Vec<Vec3>>,
+        material: Arc<dyn Material>,
+    ) -> PointCloud {
+        assert!(!points.is_empty(), "A point cloud needs at least one point");
+        assert_eq!(points.len(), radii.len());
+        if let Some(colors) = &colors {
+            assert_eq!(points.len(), colors.len());
+        }
+        assert!(
+            radii
+                .iter()
+                .all(|&radius| radius > 0.0 && radius.is_finite()),
+            "Point radii must be positive and finite"
+        );
+
+        let bboxes: Vec<AABB> = points
+            .iter()
+            .zip(&radii)
+            .map(|(point, &radius)| {
+                let radius_vector = Vec3::from_element(radius);
+                AABB::new(point - radius_vector, point + radius_vector)
+            })
+            .collect();
+        PointCloud {
+            points,
+            radii,
+            colors,
+            material,
+            bvh: IndexBvh::new(&bboxes),
+        }
+    }
+}
+
+impl Hittable for PointCloud {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.bvh.hit(ray, t_min, t_max, |index, closest_t| {
+            let (t, point, normal) = ray_sphere_intersection(
+                &self.points[index],
+                self.radii[index],
+                ray,
+                t_min,
+                closest_t,
+            )?;
+            let (u, v) = Sphere::get_uv(&normal);
+            let mut record = HitRecord::from_uv(
+                t,
+                point,
+                ray.direction,
+                normal,
+                self.material.as_ref(),
+                u,
+                v,
+            );
+            record.vertex_color = self.colors.as_ref().map(|colors| colors[index]);
+            Some(record)
+        })
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        Some(self.bvh.bounding_box())
+    }
+}
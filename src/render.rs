@@ -1,10 +1,29 @@
+use crate::atmosphere::Atmosphere;
+use crate::environment::Environment;
+use crate::lights::LightList;
 use crate::math::Vec3;
+use crate::texture::Texture;
 use crate::Hittable;
 use crate::Material;
-use rand::RngCore;
+use rand::rngs::SmallRng;
+use rand::{RngCore, SeedableRng};
 use rand_distr::{Distribution, Uniform, UnitDisc};
+use std::cell::Cell;
 use std::sync::Arc;
 
+thread_local! {
+    // Rays traced by the current thread, for the statistics.
+    static RAY_COUNT: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn count_ray() {
+    RAY_COUNT.with(|count| count.set(count.get() + 1));
+}
+
+pub fn take_ray_count() -> u64 {
+    RAY_COUNT.with(|count| count.replace(0))
+}
+
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
@@ -15,6 +34,28 @@ impl Ray {
     pub fn at(&self, t: f64) -> Vec3 {
         self.origin + t * self.direction
     }
+
+    // A generator seeded from the ray and a distance along it, for the
+    // hittables drawing random numbers (media), Hittable::hit having no
+    // generator. Rays already come from the seeded per-thread generators, so
+    // renders stay reproducible, and the distance keeps two media crossed by
+    // the same ray from drawing the same numbers.
+    pub fn rng_at(&self, t: f64) -> SmallRng {
+        let values = [
+            self.origin.x,
+            self.origin.y,
+            self.origin.z,
+            self.direction.x,
+            self.direction.y,
+            self.direction.z,
+            self.time,
+            t,
+        ];
+        let hash = values.iter().fold(0u64, |hash, value| {
+            (hash.rotate_left(5) ^ value.to_bits()).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        });
+        SmallRng::seed_from_u64(hash)
+    }
 }
 
 pub struct HitRecord<'a> {
@@ -25,9 +66,20 @@ pub struct HitRecord<'a> {
     pub material: &'a dyn Material,
     pub u: f64,
     pub v: f64,
+    // Interpolated vertex color, tinting the material's albedo.
+    pub vertex_color: Option<Vec3>,
 }
 
 impl<'a> HitRecord<'a> {
+    // The texture's value at the hit point, times its vertex color.
+    pub fn albedo(&self, texture: &dyn Texture) -> Vec3 {
+        let albedo = texture.value(self.u, self.v, &self.p);
+        match self.vertex_color {
+            Some(color) => albedo.component_mul(&color),
+            None => albedo,
+        }
+    }
+
     pub fn set_face_normal(incoming: Vec3, outward_normal: Vec3) -> (bool, Vec3) {
         let front_facing = incoming.dot(&outward_normal) < 0.0;
         let normal = {
@@ -45,7 +97,7 @@ impl<'a> HitRecord<'a> {
         incoming: Vec3,
         normal: Vec3,
         material: &dyn Material,
-    ) -> HitRecord {
+    ) -> HitRecord<'_> {
         let (front_facing, normal) = HitRecord::set_face_normal(incoming, normal);
         HitRecord {
             t,
@@ -55,6 +107,7 @@ impl<'a> HitRecord<'a> {
             material,
             u: 0.0,
             v: 0.0,
+            vertex_color: None,
         }
     }
     pub fn from_uv(
@@ -65,7 +118,7 @@ impl<'a> HitRecord<'a> {
         material: &dyn Material,
         u: f64,
         v: f64,
-    ) -> HitRecord {
+    ) -> HitRecord<'_> {
         let (front_facing, normal) = HitRecord::set_face_normal(incoming, normal);
         HitRecord {
             t,
@@ -75,6 +128,7 @@ impl<'a> HitRecord<'a> {
             material,
             u,
             v,
+            vertex_color: None,
         }
     }
 }
@@ -88,7 +142,6 @@ pub struct Camera {
 
     pub u: Vec3,
     pub v: Vec3,
-    pub w: Vec3,
 
     pub lens_radius: f64,
     pub time_begin: f64,
@@ -96,6 +149,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         eye: Vec3,
         target: Vec3,
@@ -125,7 +179,6 @@ impl Camera {
 
             u,
             v,
-            w,
 
             lower_left_corner: eye - horizontal / 2. - vertical / 2. - focus_distance * w,
             lens_radius: aperture / 2.,
@@ -149,6 +202,51 @@ impl Camera {
     }
 }
 
+// Everything needed to build a Camera except the aspect ratio, which only
+// comes from the command line once the render size is known.
+#[derive(Copy, Clone)]
+pub struct CameraSettings {
+    pub eye: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    pub vertical_fov: f64,
+    pub aperture: f64,
+    // Defaults to the eye-target distance when missing.
+    pub focus_distance: Option<f64>,
+    pub time_begin: f64,
+    pub time_end: f64,
+}
+
+impl CameraSettings {
+    pub fn looking_at(eye: Vec3, target: Vec3, vertical_fov: f64) -> CameraSettings {
+        CameraSettings {
+            eye,
+            target,
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov,
+            aperture: 0.0,
+            focus_distance: None,
+            time_begin: 0.0,
+            time_end: 1.0,
+        }
+    }
+
+    pub fn build(&self, aspect_ratio: f64) -> Camera {
+        Camera::new(
+            self.eye,
+            self.target,
+            self.up,
+            self.vertical_fov,
+            aspect_ratio,
+            self.aperture,
+            self.focus_distance
+                .unwrap_or_else(|| (self.eye - self.target).norm()),
+            self.time_begin,
+            self.time_end,
+        )
+    }
+}
+
 pub struct Subregion {
     pub x: usize,
     pub y: usize,
@@ -157,6 +255,7 @@ pub struct Subregion {
 }
 
 impl Subregion {
+    #[allow(dead_code)]
     pub fn grid_cell(
         x: usize,
         y: usize,
@@ -216,21 +315,55 @@ impl Subregion {
     }
 }
 
+// Everything rays are traced against, shared by the render threads.
+#[derive(Clone)]
+pub struct World {
+    pub objects: Arc<dyn Hittable>,
+    pub atmosphere: Option<Atmosphere>,
+    pub lights: LightList,
+    // Black when missing.
+    pub environment: Option<Arc<dyn Environment>>,
+}
+
+impl World {
+    // The closest surface hit or scattering event in the atmosphere.
+    pub fn hit(&self, ray: &Ray, t_min: f64, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
+        let closest = self.objects.hit(ray, t_min, f64::INFINITY);
+        if let Some(atmosphere) = &self.atmosphere {
+            let t_max = closest.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+            if let Some(scattering) = atmosphere.scatter_before(ray, t_min, t_max, rng) {
+                return Some(scattering);
+            }
+        }
+        closest
+    }
+
+    // The light coming from the environment in a direction.
+    pub fn background(&self, direction: &Vec3) -> Vec3 {
+        match &self.environment {
+            Some(environment) => environment.radiance(direction),
+            None => Vec3::zeros(),
+        }
+    }
+}
+
 pub struct RenderTile {
     pub region: Subregion,
     pub buffer: Vec<Vec3>,
-    pub scene: Arc<dyn Hittable>,
+    pub scene: World,
     pub camera: Camera,
+    pub rays: u64,
 }
 
 impl RenderTile {
-    pub fn new(region: Subregion, scene: Arc<dyn Hittable>, camera: Camera) -> RenderTile {
+    pub fn new(region: Subregion, scene: World, camera: Camera) -> RenderTile {
         let buffer_size = region.area();
         RenderTile {
             region,
             buffer: vec![Vec3::zeros(); buffer_size],
             scene,
             camera,
+            rays: 0,
         }
     }
 }
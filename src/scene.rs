@@ -0,0 +1,1363 @@
+// Text scene description loader.
+//
+// A scene file is a list of statements, one per line, with blocks of
+// properties between braces. `#` starts a comment that runs until the end of
+// the line.
+//
+//     camera {
+//         eye 278 278 -800
+//         target 278 278 0
+//         fov 40
+//     }
+//
+//     texture earth image { path "longlat.png" }
+//     material white lambertian { albedo 0.73 0.73 0.73 }
+//     material globe lambertian { albedo earth }
+//
+//     object sphere {
+//         center 0 1 0
+//         radius 1
+//         material globe
+//         rotate_y 45
+//         translate 0 0 2
+//     }
+//
+// Textures and materials are named so they can be shared between several
+// definitions. A texture argument accepts either a texture name or an inline
+// RGB triplet. Every object accepts the transform properties `translate x y z`,
+// `rotate_x deg`, `rotate_y deg`, `rotate_z deg` and `scale s`, applied in the
+// order they're written.
+use crate::atmosphere::AtmosphereSettings;
+use crate::bvh::FlatBvh;
+use crate::environment::{Environment, EnvironmentMap};
+use crate::gltf_import::{load_gltf, GltfError};
+use crate::hittable::*;
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::{MeshBuffers, TriangleMesh};
+use crate::noise::Perlin;
+use crate::obj::load_obj;
+use crate::ply::load_ply;
+use crate::render::CameraSettings;
+use crate::sky::PreethamSky;
+use crate::texture::*;
+use crate::voxel::{load_voxel_grid, VoxelVolume};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// The objects are left for the renderer to put in its acceleration structure.
+pub struct Scene {
+    pub objects: Vec<Arc<dyn Hittable>>,
+    pub camera: CameraSettings,
+    pub atmosphere: Option<AtmosphereSettings>,
+    pub environment: Option<Arc<dyn Environment>>,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Gltf(GltfError),
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(path, err) => write!(f, "couldn't read {} ({})", path.display(), err),
+            SceneError::Gltf(err) => err.fmt(f),
+            SceneError::Parse {
+                line,
+                column,
+                message,
+            } => write!(f, "line {}, column {}: {}", line, column, message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl Scene {
+    // glTF files are imported as a whole scene, anything else is read as a
+    // scene description.
+    pub fn from_path(path: &Path) -> Result<Scene, SceneError> {
+        let extension = path.extension().and_then(|e| e.to_str());
+        if let Some("gltf" | "glb") = extension.map(str::to_ascii_lowercase).as_deref() {
+            return Scene::from_gltf(path);
+        }
+
+        let source =
+            std::fs::read_to_string(path).map_err(|err| SceneError::Io(path.to_path_buf(), err))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Scene::parse(&source, base_dir)
+    }
+
+    // Without a camera in the file, the scene is framed from its +Z side.
+    // Punctual lights aren't imported, so files without emissive materials
+    // are lit by a default sky instead of rendering black.
+    pub fn from_gltf(path: &Path) -> Result<Scene, SceneError> {
+        let imported = load_gltf(path).map_err(SceneError::Gltf)?;
+        let world = match imported.world {
+            Some(world) => world,
+            None => {
+                return Err(SceneError::Gltf(GltfError::Invalid {
+                    path: path.to_path_buf(),
+                    message: "the scene has no meshes".to_string(),
+                }))
+            }
+        };
+
+        let camera = imported.camera.unwrap_or_else(|| {
+            let bbox = world.bounding_box(0.0, 1.0).unwrap_or_else(AABB::zeros);
+            let center = (bbox.min + bbox.max) / 2.0;
+            let radius = (bbox.max - bbox.min).norm() / 2.0;
+            let fov: f64 = 40.0;
+            let distance = radius / (fov.to_radians() / 2.0).tan();
+            CameraSettings::looking_at(center + Vec3::new(0.0, 0.0, distance), center, fov)
+        });
+
+        let environment: Option<Arc<dyn Environment>> =
+            if collect_lights(std::slice::from_ref(&world)).is_empty() {
+                Some(Arc::new(PreethamSky::new(
+                    &Vec3::new(1.0, 2.0, 1.0),
+                    3.0,
+                    0.53f64.to_radians(),
+                    1.0,
+                )))
+            } else {
+                None
+            };
+
+        Ok(Scene {
+            objects: vec![world],
+            camera,
+            atmosphere: None,
+            environment,
+        })
+    }
+
+    // Relative paths found in the scene (image textures...) are resolved
+    // against base_dir.
+    pub fn parse(source: &str, base_dir: &Path) -> Result<Scene, SceneError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            base_dir: base_dir.to_path_buf(),
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            shapes: HashMap::new(),
+            objects: vec![],
+            camera: None,
+            atmosphere: None,
+            environment: None,
+            rng: SmallRng::seed_from_u64(0xDEADBEEF),
+        };
+        parser.parse_file()?;
+
+        let end = parser.peek().clone();
+        let camera = match parser.camera {
+            Some(camera) => camera,
+            None => return Err(end.error("the scene has no camera block")),
+        };
+        if parser.objects.is_empty() {
+            return Err(end.error("the scene has no objects"));
+        }
+
+        Ok(Scene {
+            objects: parser.objects,
+            camera,
+            atmosphere: parser.atmosphere,
+            environment: parser.environment,
+        })
+    }
+}
+
+// - Lexer -
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Number(f64),
+    Str(String),
+    OpenBrace,
+    CloseBrace,
+    Newline,
+    Eof,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> SceneError {
+        SceneError::Parse {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(word) => format!("`{}`", word),
+            TokenKind::Number(number) => format!("number {}", number),
+            TokenKind::Str(string) => format!("string \"{}\"", string),
+            TokenKind::OpenBrace => "`{`".to_string(),
+            TokenKind::CloseBrace => "`}`".to_string(),
+            TokenKind::Newline => "end of line".to_string(),
+            TokenKind::Eof => "end of file".to_string(),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, SceneError> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    let mut column = 1;
+
+    while let Some(&c) = chars.peek() {
+        let (start_line, start_column) = (line, column);
+        let token = |kind| Token {
+            kind,
+            line: start_line,
+            column: start_column,
+        };
+        match c {
+            '\n' => {
+                chars.next();
+                tokens.push(token(TokenKind::Newline));
+                line += 1;
+                column = 1;
+            }
+            '#' => {
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                    column += 1;
+                }
+            }
+            '{' | '}' => {
+                chars.next();
+                column += 1;
+                tokens.push(token(if c == '{' {
+                    TokenKind::OpenBrace
+                } else {
+                    TokenKind::CloseBrace
+                }));
+            }
+            '"' => {
+                chars.next();
+                column += 1;
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => {
+                            column += 1;
+                            break;
+                        }
+                        Some('\n') | None => {
+                            return Err(token(TokenKind::Eof).error("unterminated string"))
+                        }
+                        Some(c) => {
+                            column += 1;
+                            string.push(c);
+                        }
+                    }
+                }
+                tokens.push(token(TokenKind::Str(string)));
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+                column += 1;
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '{' || c == '}' || c == '#' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                    column += 1;
+                }
+                // A leading dot only starts a number before a digit, so
+                // unquoted relative paths like ./mesh.obj stay words.
+                let mut leading = word.chars();
+                let looks_numeric = match (leading.next(), leading.next()) {
+                    (Some('.'), second) => second.is_some_and(|c| c.is_ascii_digit()),
+                    (Some(first), _) => first.is_ascii_digit() || first == '-' || first == '+',
+                    (None, _) => false,
+                };
+                if looks_numeric {
+                    match word.parse::<f64>() {
+                        Ok(number) => tokens.push(token(TokenKind::Number(number))),
+                        Err(_) => {
+                            return Err(
+                                token(TokenKind::Eof).error(format!("invalid number `{}`", word))
+                            )
+                        }
+                    }
+                } else {
+                    tokens.push(token(TokenKind::Word(word)));
+                }
+            }
+        }
+    }
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        line,
+        column,
+    });
+    Ok(tokens)
+}
+
+// - Properties -
+
+#[derive(Clone, Debug)]
+struct Property {
+    key: Token,
+    name: String,
+    args: Vec<Token>,
+}
+
+// Properties of a block, checked off as they're read so leftovers can be
+// reported as unknown.
+struct Properties {
+    owner: String,
+    entries: Vec<Property>,
+    used: Vec<bool>,
+}
+
+impl Properties {
+    fn find(&mut self, name: &str) -> Option<&Property> {
+        let index = self.entries.iter().position(|entry| entry.name == name)?;
+        self.used[index] = true;
+        Some(&self.entries[index])
+    }
+
+    fn numbers(property: &Property, count: usize) -> Result<Vec<f64>, SceneError> {
+        if property.args.len() != count {
+            return Err(property.key.error(format!(
+                "`{}` expects {} number(s), got {} argument(s)",
+                property.name,
+                count,
+                property.args.len()
+            )));
+        }
+        property
+            .args
+            .iter()
+            .map(|arg| match arg.kind {
+                TokenKind::Number(number) => Ok(number),
+                _ => Err(arg.error(format!("expected a number, found {}", arg.describe()))),
+            })
+            .collect()
+    }
+
+    // For the variable-length lists, like mesh buffers.
+    fn number_list(&mut self, name: &str, stride: usize) -> Result<Option<Vec<f64>>, SceneError> {
+        let property = match self.find(name) {
+            Some(property) => property,
+            None => return Ok(None),
+        };
+        if property.args.is_empty() || property.args.len() % stride != 0 {
+            return Err(property.key.error(format!(
+                "`{}` expects a multiple of {} numbers, got {}",
+                property.name,
+                stride,
+                property.args.len()
+            )));
+        }
+        Ok(Some(Properties::numbers(property, property.args.len())?))
+    }
+
+    fn missing(&self, block: &Token, name: &str) -> SceneError {
+        block.error(format!("{} is missing `{}`", self.owner, name))
+    }
+
+    fn f64_or(&mut self, name: &str, default: f64) -> Result<f64, SceneError> {
+        match self.find(name) {
+            Some(property) => Ok(Properties::numbers(property, 1)?[0]),
+            None => Ok(default),
+        }
+    }
+
+    // For counts and seeds, which can't be negative or fractional.
+    fn u32_or(&mut self, name: &str, default: u32) -> Result<u32, SceneError> {
+        let property = match self.find(name) {
+            Some(property) => property,
+            None => return Ok(default),
+        };
+        let number = Properties::numbers(property, 1)?[0];
+        if number < 0.0 || number.fract() != 0.0 || number > u32::MAX as f64 {
+            return Err(property.args[0].error(format!(
+                "`{}` expects a non-negative integer, found {}",
+                property.name, number
+            )));
+        }
+        Ok(number as u32)
+    }
+
+    fn f64(&mut self, block: &Token, name: &str) -> Result<f64, SceneError> {
+        match self.find(name) {
+            Some(property) => Ok(Properties::numbers(property, 1)?[0]),
+            None => Err(self.missing(block, name)),
+        }
+    }
+
+    fn vec2_opt(&mut self, name: &str) -> Result<Option<Vec2>, SceneError> {
+        match self.find(name) {
+            Some(property) => Ok(Some(Vec2::from_vec(Properties::numbers(property, 2)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn vec2(&mut self, block: &Token, name: &str) -> Result<Vec2, SceneError> {
+        match self.vec2_opt(name)? {
+            Some(v) => Ok(v),
+            None => Err(self.missing(block, name)),
+        }
+    }
+
+    fn vec3_opt(&mut self, name: &str) -> Result<Option<Vec3>, SceneError> {
+        match self.find(name) {
+            Some(property) => Ok(Some(Vec3::from_vec(Properties::numbers(property, 3)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn vec3(&mut self, block: &Token, name: &str) -> Result<Vec3, SceneError> {
+        match self.vec3_opt(name)? {
+            Some(v) => Ok(v),
+            None => Err(self.missing(block, name)),
+        }
+    }
+
+    fn word(&mut self, block: &Token, name: &str) -> Result<(Token, String), SceneError> {
+        let property = match self.find(name) {
+            Some(property) => property,
+            None => return Err(self.missing(block, name)),
+        };
+        match property.args.as_slice() {
+            [arg] => match &arg.kind {
+                TokenKind::Word(word) | TokenKind::Str(word) => Ok((arg.clone(), word.clone())),
+                _ => Err(arg.error(format!("expected a name, found {}", arg.describe()))),
+            },
+            _ => Err(property
+                .key
+                .error(format!("`{}` expects a single name", property.name))),
+        }
+    }
+
+    // Returns all the properties in order, for the order-dependent ones like
+    // transforms.
+    fn take_all(&mut self, names: &[&str]) -> Vec<Property> {
+        let mut taken = vec![];
+        for (index, entry) in self.entries.iter().enumerate() {
+            if names.contains(&entry.name.as_str()) {
+                self.used[index] = true;
+                taken.push(entry.clone());
+            }
+        }
+        taken
+    }
+
+    fn finish(self) -> Result<(), SceneError> {
+        for (entry, used) in self.entries.iter().zip(self.used.iter()) {
+            if !used {
+                return Err(entry.key.error(format!(
+                    "unknown property `{}` for {}",
+                    entry.name, self.owner
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// - Parser -
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    base_dir: PathBuf,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    shapes: HashMap<String, Arc<dyn Hittable>>,
+    objects: Vec<Arc<dyn Hittable>>,
+    camera: Option<CameraSettings>,
+    atmosphere: Option<AtmosphereSettings>,
+    environment: Option<Arc<dyn Environment>>,
+    rng: SmallRng,
+}
+
+const TRANSFORM_PROPERTIES: [&str; 5] = ["translate", "rotate_x", "rotate_y", "rotate_z", "scale"];
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token.kind != TokenKind::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek().kind == TokenKind::Newline {
+            self.next();
+        }
+    }
+
+    fn expect_word(&mut self, what: &str) -> Result<(Token, String), SceneError> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Word(word) => {
+                let word = word.clone();
+                Ok((token, word))
+            }
+            _ => Err(token.error(format!("expected {}, found {}", what, token.describe()))),
+        }
+    }
+
+    fn parse_file(&mut self) -> Result<(), SceneError> {
+        loop {
+            self.skip_newlines();
+            if self.peek().kind == TokenKind::Eof {
+                return Ok(());
+            }
+            let (token, keyword) = self.expect_word("a statement")?;
+            match keyword.as_str() {
+                "camera" => {
+                    if self.camera.is_some() {
+                        return Err(token.error("the camera is already defined"));
+                    }
+                    let props = self.parse_block("camera")?;
+                    self.camera = Some(Parser::build_camera(&token, props)?);
+                }
+                "atmosphere" => {
+                    if self.atmosphere.is_some() {
+                        return Err(token.error("the atmosphere is already defined"));
+                    }
+                    let props = self.parse_block("atmosphere")?;
+                    self.atmosphere = Some(self.build_atmosphere(props)?);
+                }
+                "environment" => {
+                    if self.environment.is_some() {
+                        return Err(token.error("the environment is already defined"));
+                    }
+                    let props = self.parse_block("environment")?;
+                    self.environment = Some(self.build_environment(props)?);
+                }
+                "sky" => {
+                    if self.environment.is_some() {
+                        return Err(token.error("the environment is already defined"));
+                    }
+                    let props = self.parse_block("sky")?;
+                    self.environment = Some(Parser::build_sky(props)?);
+                }
+                "texture" => {
+                    let (name_token, name) = self.expect_word("a texture name")?;
+                    let (kind_token, kind) = self.expect_word("a texture type")?;
+                    let props = self.parse_block(&format!("texture `{}`", name))?;
+                    let texture = self.build_texture(&kind_token, &kind, props)?;
+                    if self.textures.insert(name.clone(), texture).is_some() {
+                        return Err(
+                            name_token.error(format!("texture `{}` is already defined", name))
+                        );
+                    }
+                }
+                "material" => {
+                    let (name_token, name) = self.expect_word("a material name")?;
+                    let (kind_token, kind) = self.expect_word("a material type")?;
+                    let props = self.parse_block(&format!("material `{}`", name))?;
+                    let material = self.build_material(&kind_token, &kind, props)?;
+                    if self.materials.insert(name.clone(), material).is_some() {
+                        return Err(
+                            name_token.error(format!("material `{}` is already defined", name))
+                        );
+                    }
+                }
+                "object" => {
+                    let (kind_token, kind) = self.expect_word("an object type")?;
+                    let props = self.parse_block(&kind)?;
+                    let object = self.build_object(&kind_token, &kind, props)?;
+                    self.objects.push(object);
+                }
+                "shape" => {
+                    let (name_token, name) = self.expect_word("a shape name")?;
+                    let (kind_token, kind) = self.expect_word("an object type")?;
+                    let props = self.parse_block(&format!("shape `{}`", name))?;
+                    let shape = self.build_object(&kind_token, &kind, props)?;
+                    if self.shapes.insert(name.clone(), shape).is_some() {
+                        return Err(
+                            name_token.error(format!("shape `{}` is already defined", name))
+                        );
+                    }
+                }
+                _ => return Err(token.error(format!("unknown statement `{}`", keyword))),
+            }
+        }
+    }
+
+    fn parse_block(&mut self, owner: &str) -> Result<(Token, Properties), SceneError> {
+        let open = self.next();
+        if open.kind != TokenKind::OpenBrace {
+            return Err(open.error(format!("expected `{{`, found {}", open.describe())));
+        }
+
+        let mut entries: Vec<Property> = vec![];
+        loop {
+            self.skip_newlines();
+            let token = self.next();
+            let name = match &token.kind {
+                TokenKind::CloseBrace => break,
+                TokenKind::Word(name) => name.clone(),
+                _ => {
+                    return Err(token.error(format!(
+                        "expected a property or `}}`, found {}",
+                        token.describe()
+                    )))
+                }
+            };
+            let is_transform = TRANSFORM_PROPERTIES.contains(&name.as_str());
+            if !is_transform && entries.iter().any(|entry| entry.name == name) {
+                return Err(token.error(format!("`{}` is set twice", name)));
+            }
+
+            let mut args = vec![];
+            loop {
+                match self.peek().kind {
+                    TokenKind::Newline | TokenKind::CloseBrace => break,
+                    TokenKind::Eof | TokenKind::OpenBrace => {
+                        let unexpected = self.peek();
+                        return Err(unexpected
+                            .error(format!("unexpected {} in block", unexpected.describe())));
+                    }
+                    _ => args.push(self.next()),
+                }
+            }
+            entries.push(Property {
+                key: token,
+                name,
+                args,
+            });
+        }
+
+        let used = vec![false; entries.len()];
+        Ok((
+            open,
+            Properties {
+                owner: owner.to_string(),
+                entries,
+                used,
+            },
+        ))
+    }
+
+    fn build_camera(
+        keyword: &Token,
+        (block, mut props): (Token, Properties),
+    ) -> Result<CameraSettings, SceneError> {
+        let eye = props.vec3(&block, "eye")?;
+        let target = props.vec3(&block, "target")?;
+        let mut camera = CameraSettings::looking_at(eye, target, props.f64_or("fov", 40.)?);
+        if let Some(up) = props.vec3_opt("up")? {
+            camera.up = up;
+        }
+        camera.aperture = props.f64_or("aperture", 0.)?;
+        if let Some(property) = props.find("focus_distance") {
+            camera.focus_distance = Some(Properties::numbers(property, 1)?[0]);
+        }
+        if let Some(property) = props.find("shutter") {
+            let times = Properties::numbers(property, 2)?;
+            camera.time_begin = times[0];
+            camera.time_end = times[1];
+        }
+        if (camera.eye - camera.target).norm() == 0.0 {
+            return Err(keyword.error("the camera's eye and target are the same point"));
+        }
+        props.finish()?;
+        Ok(camera)
+    }
+
+    fn build_atmosphere(
+        &self,
+        (block, mut props): (Token, Properties),
+    ) -> Result<AtmosphereSettings, SceneError> {
+        let density = props.f64(&block, "density")?;
+        if density <= 0.0 {
+            return Err(block.error("the density must be positive"));
+        }
+        let anisotropy = props.f64_or("anisotropy", 0.)?;
+        if anisotropy.abs() >= 1.0 {
+            return Err(block.error("the anisotropy must be between -1 and 1"));
+        }
+        let albedo = self.texture_arg(&mut props, &block, "albedo")?;
+        props.finish()?;
+        Ok(AtmosphereSettings {
+            density,
+            albedo,
+            anisotropy,
+        })
+    }
+
+    fn build_environment(
+        &self,
+        (block, mut props): (Token, Properties),
+    ) -> Result<Arc<dyn Environment>, SceneError> {
+        let (token, path) = props.word(&block, "path")?;
+        let path = self.resolve_path(&path);
+        let rotation = props.f64_or("rotation", 0.)?.to_radians();
+        let intensity = props.f64_or("intensity", 1.)?;
+        if intensity < 0.0 {
+            return Err(block.error("the intensity can't be negative"));
+        }
+        props.finish()?;
+        match EnvironmentMap::open(&path, rotation, intensity) {
+            Ok(environment) => Ok(Arc::new(environment)),
+            Err(err) => Err(token.error(format!(
+                "couldn't open the environment map at {} ({})",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    fn build_sky(
+        (block, mut props): (Token, Properties),
+    ) -> Result<Arc<dyn Environment>, SceneError> {
+        let sun_direction = props.vec3(&block, "sun_direction")?;
+        if sun_direction.norm() == 0.0 || sun_direction.normalize().y <= 0.0 {
+            return Err(block.error("the sun must be above the horizon"));
+        }
+        let turbidity = props.f64_or("turbidity", 3.)?;
+        if !(1.7..=10.0).contains(&turbidity) {
+            return Err(block.error("the turbidity must be between 1.7 and 10"));
+        }
+        let sun_size = props.f64_or("sun_size", 0.53)?;
+        if sun_size <= 0.0 || sun_size >= 90.0 {
+            return Err(block.error("the sun's size must be between 0 and 90 degrees"));
+        }
+        let intensity = props.f64_or("intensity", 1.)?;
+        if intensity < 0.0 {
+            return Err(block.error("the intensity can't be negative"));
+        }
+        props.finish()?;
+        Ok(Arc::new(PreethamSky::new(
+            &sun_direction,
+            turbidity,
+            sun_size.to_radians(),
+            intensity,
+        )))
+    }
+
+    // A texture argument is either the name of a texture or an inline color.
+    fn texture_arg(
+        &self,
+        props: &mut Properties,
+        block: &Token,
+        name: &str,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        let property = match props.find(name) {
+            Some(property) => property.clone(),
+            None => return Err(props.missing(block, name)),
+        };
+        match property.args.as_slice() {
+            [arg] => match &arg.kind {
+                TokenKind::Word(texture_name) => match self.textures.get(texture_name) {
+                    Some(texture) => Ok(texture.clone()),
+                    None => Err(arg.error(format!("unknown texture `{}`", texture_name))),
+                },
+                _ => Err(arg.error(format!(
+                    "expected a texture name or a color, found {}",
+                    arg.describe()
+                ))),
+            },
+            _ => {
+                let color = Properties::numbers(&property, 3)?;
+                Ok(Arc::new(SolidColor::new(color[0], color[1], color[2])))
+            }
+        }
+    }
+
+    fn material_arg(
+        &self,
+        props: &mut Properties,
+        block: &Token,
+    ) -> Result<Arc<dyn Material>, SceneError> {
+        let (token, name) = props.word(block, "material")?;
+        match self.materials.get(&name) {
+            Some(material) => Ok(material.clone()),
+            None => Err(token.error(format!("unknown material `{}`", name))),
+        }
+    }
+
+    fn texture_arg_opt(
+        &self,
+        props: &mut Properties,
+        block: &Token,
+        name: &str,
+    ) -> Result<Option<Arc<dyn Texture>>, SceneError> {
+        if props.find(name).is_none() {
+            return Ok(None);
+        }
+        self.texture_arg(props, block, name).map(Some)
+    }
+
+    fn shape_arg(
+        &self,
+        props: &mut Properties,
+        block: &Token,
+        key: &str,
+    ) -> Result<Arc<dyn Hittable>, SceneError> {
+        let (token, name) = props.word(block, key)?;
+        match self.shapes.get(&name) {
+            Some(shape) => Ok(shape.clone()),
+            None => Err(token.error(format!("unknown shape `{}`", name))),
+        }
+    }
+
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    fn seeded_perlin(props: &mut Properties) -> Result<Perlin, SceneError> {
+        let seed = props.u32_or("seed", 0)?;
+        Ok(Perlin::new(&mut SmallRng::seed_from_u64(seed as u64)))
+    }
+
+    fn build_texture(
+        &mut self,
+        kind_token: &Token,
+        kind: &str,
+        (block, mut props): (Token, Properties),
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        let texture: Arc<dyn Texture> = match kind {
+            "solid" => Arc::new(SolidColor {
+                albedo: props.vec3(&block, "color")?,
+            }),
+            "checker" => Arc::new(Checkerboard {
+                albedo_odd: self.texture_arg(&mut props, &block, "odd")?,
+                albedo_even: self.texture_arg(&mut props, &block, "even")?,
+            }),
+            "noise" => Arc::new(Noise {
+                perlin: Parser::seeded_perlin(&mut props)?,
+                scale: props.f64_or("scale", 1.)?,
+            }),
+            "turbulence" => Arc::new(TurbulentNoise {
+                perlin: Parser::seeded_perlin(&mut props)?,
+                scale: props.f64_or("scale", 1.)?,
+                depth: props.u32_or("depth", 7)?,
+            }),
+            "marble" => Arc::new(MarbleNoise {
+                perlin: Parser::seeded_perlin(&mut props)?,
+                scale: props.f64_or("scale", 1.)?,
+                depth: props.u32_or("depth", 7)?,
+            }),
+            "image" => {
+                let (token, path) = props.word(&block, "path")?;
+                let path = self.resolve_path(&path);
+                match ImageTexture::open(&path.to_string_lossy()) {
+                    Ok(texture) => Arc::new(texture),
+                    Err(err) => {
+                        return Err(token.error(format!(
+                            "couldn't open the picture at {} ({})",
+                            path.display(),
+                            err
+                        )))
+                    }
+                }
+            }
+            _ => return Err(kind_token.error(format!("unknown texture type `{}`", kind))),
+        };
+        props.finish()?;
+        Ok(texture)
+    }
+
+    fn build_material(
+        &mut self,
+        kind_token: &Token,
+        kind: &str,
+        (block, mut props): (Token, Properties),
+    ) -> Result<Arc<dyn Material>, SceneError> {
+        let material: Arc<dyn Material> = match kind {
+            "lambertian" => Arc::new(Lambertian {
+                albedo: self.texture_arg(&mut props, &block, "albedo")?,
+            }),
+            "metal" => Arc::new(Metal {
+                albedo: self.texture_arg(&mut props, &block, "albedo")?,
+                roughness: props.f64_or("roughness", 0.)?,
+            }),
+            "dielectric" => Arc::new(Dielectric {
+                ior: props.f64_or("ior", 1.5)?,
+            }),
+            "diffuse_light" => Arc::new(DiffuseLight {
+                emissive: self.texture_arg(&mut props, &block, "emit")?,
+            }),
+            "isotropic" => Arc::new(Isotropic {
+                albedo: self.texture_arg(&mut props, &block, "albedo")?,
+                emission: self.texture_arg_opt(&mut props, &block, "emit")?,
+            }),
+            _ => return Err(kind_token.error(format!("unknown material type `{}`", kind))),
+        };
+        props.finish()?;
+        Ok(material)
+    }
+
+    fn build_object(
+        &mut self,
+        kind_token: &Token,
+        kind: &str,
+        (block, mut props): (Token, Properties),
+    ) -> Result<Arc<dyn Hittable>, SceneError> {
+        let transforms = props.take_all(&TRANSFORM_PROPERTIES);
+        let object: Arc<dyn Hittable> = match kind {
+            "sphere" => Arc::new(Sphere {
+                center: props.vec3(&block, "center")?,
+                radius: props.f64(&block, "radius")?,
+                material: self.material_arg(&mut props, &block)?,
+            }),
+            "moving_sphere" => Arc::new(MovingSphere {
+                center_begin: props.vec3(&block, "center_begin")?,
+                center_end: props.vec3(&block, "center_end")?,
+                time_begin: props.f64_or("time_begin", 0.)?,
+                time_end: props.f64_or("time_end", 1.)?,
+                radius: props.f64(&block, "radius")?,
+                material: self.material_arg(&mut props, &block)?,
+            }),
+            "xy_rect" => Arc::new(XyPlane {
+                min: props.vec2(&block, "min")?,
+                max: props.vec2(&block, "max")?,
+                k: props.f64(&block, "k")?,
+                material: self.material_arg(&mut props, &block)?,
+            }),
+            "xz_rect" => Arc::new(XzPlane {
+                min: props.vec2(&block, "min")?,
+                max: props.vec2(&block, "max")?,
+                k: props.f64(&block, "k")?,
+                material: self.material_arg(&mut props, &block)?,
+            }),
+            "yz_rect" => Arc::new(YzPlane {
+                min: props.vec2(&block, "min")?,
+                max: props.vec2(&block, "max")?,
+                k: props.f64(&block, "k")?,
+                material: self.material_arg(&mut props, &block)?,
+            }),
+            "triangle" => {
+                let vertices = [
+                    props.vec3(&block, "a")?,
+                    props.vec3(&block, "b")?,
+                    props.vec3(&block, "c")?,
+                ];
+                let normals = match props.vec3_opt("normal_a")? {
+                    Some(normal_a) => Some([
+                        normal_a,
+                        props.vec3(&block, "normal_b")?,
+                        props.vec3(&block, "normal_c")?,
+                    ]),
+                    None => None,
+                };
+                let uvs = match props.vec2_opt("uv_a")? {
+                    Some(uv_a) => Some([
+                        uv_a,
+                        props.vec2(&block, "uv_b")?,
+                        props.vec2(&block, "uv_c")?,
+                    ]),
+                    None => None,
+                };
+                Arc::new(Triangle {
+                    vertices,
+                    normals,
+                    uvs,
+                    material: self.material_arg(&mut props, &block)?,
+                })
+            }
+            "mesh" => self.build_mesh(&block, &mut props)?,
+            "obj" => {
+                let (token, path) = props.word(&block, "path")?;
+                let material = match props.find("material") {
+                    Some(_) => Some(self.material_arg(&mut props, &block)?),
+                    None => None,
+                };
+                let meshes = match load_obj(&self.resolve_path(&path), material) {
+                    Ok(meshes) => meshes,
+                    Err(err) => return Err(token.error(err.to_string())),
+                };
+                let mut meshes: Vec<Arc<dyn Hittable>> = meshes
+                    .into_iter()
+                    .map(|mesh| Arc::new(mesh) as Arc<dyn Hittable>)
+                    .collect();
+                match meshes.len() {
+                    0 => return Err(token.error(format!("{} has no faces", path))),
+                    1 => meshes.pop().unwrap(),
+                    _ => Arc::new(FlatBvh::new(&meshes, 0.0, f64::INFINITY)),
+                }
+            }
+            "ply" => {
+                let (token, path) = props.word(&block, "path")?;
+                let radius = props.f64_or("radius", 0.01)?;
+                if !(radius > 0.0 && radius.is_finite()) {
+                    return Err(block.error("the point radius must be positive and finite"));
+                }
+                let material = match props.find("material") {
+                    Some(_) => Some(self.material_arg(&mut props, &block)?),
+                    None => None,
+                };
+                match load_ply(&self.resolve_path(&path), material, radius) {
+                    Ok(object) => object,
+                    Err(err) => return Err(token.error(err.to_string())),
+                }
+            }
+            "gltf" => {
+                let (token, path) = props.word(&block, "path")?;
+                match load_gltf(&self.resolve_path(&path)) {
+                    Ok(imported) => match imported.world {
+                        Some(world) => world,
+                        None => return Err(token.error(format!("{} has no meshes", path))),
+                    },
+                    Err(err) => return Err(token.error(err.to_string())),
+                }
+            }
+            "instance" => self.shape_arg(&mut props, &block, "shape")?,
+            "constant_medium" => {
+                let boundary = self.shape_arg(&mut props, &block, "boundary")?;
+                let density = props.f64(&block, "density")?;
+                if density <= 0.0 {
+                    return Err(block.error("the density must be positive"));
+                }
+                let albedo = self.texture_arg(&mut props, &block, "albedo")?;
+                let emission = self.texture_arg_opt(&mut props, &block, "emit")?;
+                Arc::new(ConstantMedium::new(boundary, density, albedo, emission))
+            }
+            "medium" => {
+                let boundary = self.shape_arg(&mut props, &block, "boundary")?;
+                let density = props.f64(&block, "density")?;
+                if density <= 0.0 {
+                    return Err(block.error("the density must be positive"));
+                }
+                Arc::new(HeterogeneousMedium {
+                    boundary,
+                    field: self.texture_arg(&mut props, &block, "field")?,
+                    density,
+                    scale: props.f64_or("scale", 1.)?,
+                    offset: props.vec3_opt("offset")?.unwrap_or_else(Vec3::zeros),
+                    phase_function: Arc::new(Isotropic {
+                        albedo: self.texture_arg(&mut props, &block, "albedo")?,
+                        emission: self.texture_arg_opt(&mut props, &block, "emit")?,
+                    }),
+                })
+            }
+            "voxels" => {
+                let (token, path) = props.word(&block, "path")?;
+                let resolution = match props.vec3_opt("resolution")? {
+                    Some(resolution) => {
+                        if resolution
+                            .iter()
+                            .any(|size| *size < 1.0 || size.fract() != 0.0)
+                        {
+                            return Err(block.error("the resolution must be positive integers"));
+                        }
+                        Some([
+                            resolution.x as usize,
+                            resolution.y as usize,
+                            resolution.z as usize,
+                        ])
+                    }
+                    None => None,
+                };
+                let min = props.vec3_opt("min")?.unwrap_or_else(Vec3::zeros);
+                let max = props
+                    .vec3_opt("max")?
+                    .unwrap_or_else(|| Vec3::from_element(1.));
+                if (max - min).min() <= 0.0 {
+                    return Err(block.error("`max` must be above `min` on every axis"));
+                }
+                let density = props.f64(&block, "density")?;
+                if density <= 0.0 {
+                    return Err(block.error("the density must be positive"));
+                }
+                let grid = match load_voxel_grid(&self.resolve_path(&path), resolution) {
+                    Ok(grid) => grid,
+                    Err(err) => return Err(token.error(err.to_string())),
+                };
+                Arc::new(VoxelVolume::new(
+                    grid,
+                    AABB::new(min, max),
+                    density,
+                    Arc::new(Isotropic {
+                        albedo: self.texture_arg(&mut props, &block, "albedo")?,
+                        emission: self.texture_arg_opt(&mut props, &block, "emit")?,
+                    }),
+                ))
+            }
+            "cube" => {
+                let min = props.vec3(&block, "min")?;
+                let max = props.vec3(&block, "max")?;
+                let material = self.material_arg(&mut props, &block)?;
+                Arc::new(Cube::new(min, max, material, &mut self.rng))
+            }
+            _ => return Err(kind_token.error(format!("unknown object type `{}`", kind))),
+        };
+        props.finish()?;
+        Parser::apply_transforms(object, &transforms)
+    }
+
+    fn build_mesh(
+        &self,
+        block: &Token,
+        props: &mut Properties,
+    ) -> Result<Arc<dyn Hittable>, SceneError> {
+        let positions: Vec<Vec3> = match props.number_list("positions", 3)? {
+            Some(numbers) => numbers.chunks(3).map(Vec3::from_row_slice).collect(),
+            None => return Err(props.missing(block, "positions")),
+        };
+        let normals: Option<Vec<Vec3>> = props
+            .number_list("normals", 3)?
+            .map(|numbers| numbers.chunks(3).map(Vec3::from_row_slice).collect());
+        let uvs: Option<Vec<Vec2>> = props
+            .number_list("uvs", 2)?
+            .map(|numbers| numbers.chunks(2).map(Vec2::from_row_slice).collect());
+        let faces = match props.number_list("faces", 3)? {
+            Some(faces) => faces,
+            None => return Err(props.missing(block, "faces")),
+        };
+
+        let vertex_count = positions.len();
+        if normals.as_ref().is_some_and(|n| n.len() != vertex_count)
+            || uvs.as_ref().is_some_and(|uv| uv.len() != vertex_count)
+        {
+            return Err(block.error("a mesh needs as many normals and UVs as positions"));
+        }
+        if let Some(index) = faces
+            .iter()
+            .find(|&&i| i < 0.0 || i.fract() != 0.0 || i as usize >= vertex_count)
+        {
+            return Err(block.error(format!("invalid vertex index {} in `faces`", index)));
+        }
+        let triangles = faces
+            .chunks(3)
+            .map(|face| [face[0] as u32, face[1] as u32, face[2] as u32])
+            .collect();
+
+        let buffers = Arc::new(MeshBuffers {
+            positions,
+            normals,
+            uvs,
+            colors: None,
+        });
+        Ok(Arc::new(TriangleMesh::new(
+            buffers,
+            triangles,
+            self.material_arg(props, block)?,
+        )))
+    }
+
+    fn apply_transforms(
+        object: Arc<dyn Hittable>,
+        transforms: &[Property],
+    ) -> Result<Arc<dyn Hittable>, SceneError> {
+        if transforms.is_empty() {
+            return Ok(object);
+        }
+
+        let mut matrix = Mat4::identity();
+        for transform in transforms {
+            let step = match transform.name.as_str() {
+                "translate" => {
+                    Mat4::new_translation(&Vec3::from_vec(Properties::numbers(transform, 3)?))
+                }
+                "rotate_x" | "rotate_y" | "rotate_z" => {
+                    let angle = Properties::numbers(transform, 1)?[0].to_radians();
+                    let axis = match transform.name.as_str() {
+                        "rotate_x" => Vec3::x_axis(),
+                        "rotate_y" => Vec3::y_axis(),
+                        _ => Vec3::z_axis(),
+                    };
+                    Mat4::from_axis_angle(&axis, angle)
+                }
+                "scale" => {
+                    let factor = Properties::numbers(transform, 1)?[0];
+                    if factor == 0.0 {
+                        return Err(transform.key.error("the scale factor can't be zero"));
+                    }
+                    Mat4::new_scaling(factor)
+                }
+                _ => unreachable!(),
+            };
+            matrix = step * matrix;
+        }
+        Ok(Arc::new(Transform::new(&matrix, object)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    fn parse_error(source: &str) -> (usize, usize, String) {
+        match Scene::parse(source, Path::new(".")) {
+            Ok(_) => panic!("the scene parsed"),
+            Err(SceneError::Parse {
+                line,
+                column,
+                message,
+            }) => (line, column, message),
+            Err(err) => panic!("unexpected error {}", err),
+        }
+    }
+
+    const CAMERA: &str = "camera {\n    eye 0 0 5\n    target 0 0 0\n}\n";
+
+    #[test]
+    fn tokenizes_words_numbers_and_strings() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("object sphere { radius -1.5e1 } # comment\npath \"a b.png\""),
+            vec![
+                Word("object".to_string()),
+                Word("sphere".to_string()),
+                OpenBrace,
+                Word("radius".to_string()),
+                Number(-15.0),
+                CloseBrace,
+                Newline,
+                Word("path".to_string()),
+                Str("a b.png".to_string()),
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn leading_dots_only_start_numbers_before_digits() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds(".5 ./mesh.obj ../up.obj"),
+            vec![
+                Number(0.5),
+                Word("./mesh.obj".to_string()),
+                Word("../up.obj".to_string()),
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_know_where_they_are() {
+        let tokens = tokenize("a\n  b {").unwrap();
+        let positions: Vec<(usize, usize)> = tokens
+            .iter()
+            .map(|token| (token.line, token.column))
+            .collect();
+        assert_eq!(positions, vec![(1, 1), (1, 2), (2, 3), (2, 5), (2, 6)]);
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        match tokenize("path \"open\nend") {
+            Err(SceneError::Parse {
+                line,
+                column,
+                message,
+            }) => assert_eq!(
+                (line, column, message.as_str()),
+                (1, 6, "unterminated string")
+            ),
+            _ => panic!("the string was accepted"),
+        }
+        match tokenize("radius 1.2.3") {
+            Err(SceneError::Parse { message, .. }) => {
+                assert_eq!(message, "invalid number `1.2.3`")
+            }
+            _ => panic!("the number was accepted"),
+        }
+    }
+
+    #[test]
+    fn parses_a_scene() {
+        let source = format!(
+            "{}material red lambertian {{ albedo 1 0 0 }}\n\
+             object sphere {{\n    center 0 0 0\n    radius 1\n    material red\n}}\n",
+            CAMERA
+        );
+        let scene = match Scene::parse(&source, Path::new(".")) {
+            Ok(scene) => scene,
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(scene.objects.len(), 1);
+        assert!(scene.atmosphere.is_none());
+    }
+
+    #[test]
+    fn reports_where_scenes_go_wrong() {
+        let (line, _, message) = parse_error("object xz_rect {\n    k 0\n}\n");
+        assert_eq!((line, message.as_str()), (1, "xz_rect is missing `min`"));
+        let (line, _, message) = parse_error("material red lambertian { albedo 1 0 0 }\n");
+        assert_eq!(
+            (line, message.as_str()),
+            (2, "the scene has no camera block")
+        );
+
+        let (line, column, message) = parse_error(&format!(
+            "{}object sphere {{\n    center 0 0\n    radius 1\n}}\n",
+            CAMERA
+        ));
+        assert_eq!((line, column), (6, 5));
+        assert_eq!(message, "`center` expects 3 number(s), got 2 argument(s)");
+
+        let (_, _, message) = parse_error(&format!(
+            "{}object sphere {{\n    center 0 0 0\n    radius 1\n    material nope\n}}\n",
+            CAMERA
+        ));
+        assert_eq!(message, "unknown material `nope`");
+
+        let (_, _, message) = parse_error(&format!("{}teapot\n", CAMERA));
+        assert_eq!(message, "unknown statement `teapot`");
+    }
+
+    #[test]
+    fn counts_and_seeds_are_non_negative_integers() {
+        for (property, value) in [("seed", "-1"), ("depth", "2.5")] {
+            let (_, column, message) = parse_error(&format!(
+                "{}texture t turbulence {{ {} {} }}\n",
+                CAMERA, property, value
+            ));
+            assert_eq!(column, 25 + property.len());
+            assert_eq!(
+                message,
+                format!(
+                    "`{}` expects a non-negative integer, found {}",
+                    property,
+                    value.parse::<f64>().unwrap()
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,486 @@
+// Built-in scenes, selectable by name from the command line.
+use crate::hittable::*;
+use crate::material::*;
+use crate::math::*;
+use crate::render::CameraSettings;
+use crate::scene::Scene;
+use crate::texture::*;
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Uniform};
+use std::sync::Arc;
+
+pub struct BuiltinScene {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub build: fn() -> Scene,
+}
+
+pub const DEFAULT_SCENE: &str = "cornell_box";
+
+pub static BUILTIN_SCENES: [BuiltinScene; 4] = [
+    BuiltinScene {
+        name: "cornell_box",
+        description: "The Cornell box from The Next Week, with two rotated boxes",
+        build: || Scene {
+            objects: cornell_box(),
+            camera: CameraSettings::looking_at(
+                Vec3::new(278., 278., -800.),
+                Vec3::new(278., 278., 0.),
+                40.,
+            ),
+            atmosphere: None,
+            environment: None,
+        },
+    },
+    BuiltinScene {
+        name: "cornell_smoke",
+        description: "The Cornell box from The Next Week, with boxes of smoke",
+        build: || Scene {
+            objects: cornell_smoke(),
+            camera: CameraSettings::looking_at(
+                Vec3::new(278., 278., -800.),
+                Vec3::new(278., 278., 0.),
+                40.,
+            ),
+            atmosphere: None,
+            environment: None,
+        },
+    },
+    BuiltinScene {
+        name: "book_cover",
+        description: "Random spheres around the three big ones, with emissive spheres",
+        build: || Scene {
+            objects: book_cover_scene(),
+            camera: CameraSettings::looking_at(Vec3::new(0.0, 2.0, -10.0), Vec3::zeros(), 60.),
+            atmosphere: None,
+            environment: None,
+        },
+    },
+    BuiltinScene {
+        name: "wave",
+        description: "Rows of moving spheres over a checkered ground",
+        build: || Scene {
+            objects: wave_scene(),
+            camera: CameraSettings::looking_at(Vec3::new(0.0, 8.0, -30.0), Vec3::zeros(), 60.),
+            atmosphere: None,
+            environment: None,
+        },
+    },
+];
+
+pub fn find_builtin_scene(name: &str) -> Option<&'static BuiltinScene> {
+    BUILTIN_SCENES.iter().find(|scene| scene.name == name)
+}
+
+fn earth(center: Vec3, radius: f64) -> Arc<Sphere> {
+    let earth_texture = Arc::new(ImageTexture::from_path("data/longlat.png"));
+    let earth_mat = Arc::new(Lambertian {
+        albedo: earth_texture,
+    });
+    Arc::new(Sphere {
+        center,
+        radius,
+        material: earth_mat,
+    })
+}
+
+// Hehehe
+fn flat_earth(min: Vec2, max: Vec2, k: f64) -> Arc<XzPlane> {
+    let earth_texture = Arc::new(ImageTexture::from_path("data/longlat.png"));
+    let earth_mat = Arc::new(Lambertian {
+        albedo: earth_texture,
+    });
+    Arc::new(XzPlane {
+        min,
+        max,
+        k,
+        material: earth_mat,
+    })
+}
+
+fn wave_scene() -> Vec<Arc<dyn Hittable>> {
+    let lambertian: Arc<dyn Material> = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.2, 0.4, 0.6)),
+    });
+
+    let checker = Arc::new(Checkerboard {
+        albedo_odd: Arc::new(SolidColor::new(0.2, 0.4, 0.6)),
+        albedo_even: Arc::new(SolidColor::new(0.6, 0.6, 0.2)),
+    });
+
+    let lambertian_2: Arc<dyn Material> = Arc::new(Lambertian { albedo: checker });
+    let metal: Arc<dyn Material> = Arc::new(Metal {
+        albedo: Arc::new(SolidColor::new(0.7, 0.6, 0.5)),
+        roughness: 0.0,
+    });
+    let glass: Arc<dyn Material> = Arc::new(Dielectric { ior: 1.5 });
+
+    // No need for a hittable_list. A simple vector is largely enough for the process.
+    // A scene load/save could be interesting to add.
+    let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
+    objects.push(Arc::new(Sphere {
+        center: Vec3::new(0.0, -1005.0, 0.0),
+        radius: 1000.0,
+        material: lambertian_2,
+    }));
+    for y in (-5..5).step_by(3) {
+        for x in -10..10 {
+            let r = f64::hypot(x as f64, y as f64);
+            let begin = Vec3::new((x * 3) as f64, 0.0, (y * 3) as f64);
+            objects.push(Arc::new(MovingSphere {
+                center_begin: begin,
+                center_end: begin + Vec3::new(0.0, 1.0, 0.0),
+                radius: r / 5. + 0.2,
+                material: lambertian.clone(),
+                time_begin: 0.0,
+                time_end: 1.0,
+            }));
+            let begin = Vec3::new((x * 3) as f64, 0.0, (y * 3 + 3) as f64);
+            objects.push(Arc::new(MovingSphere {
+                center_begin: begin,
+                center_end: begin + Vec3::new(0.0, 1.0, 0.0),
+                radius: r / 5. + 0.2,
+                material: metal.clone(),
+                time_begin: 0.0,
+                time_end: 1.0,
+            }));
+            let begin = Vec3::new((x * 3) as f64, 0.0, (y * 3 + 6) as f64);
+            objects.push(Arc::new(MovingSphere {
+                center_begin: begin,
+                center_end: begin + Vec3::new(0.0, 1.0, 0.0),
+                radius: r / 5. + 0.2,
+                material: glass.clone(),
+                time_begin: 0.0,
+                time_end: 1.0,
+            }));
+        }
+    }
+
+    objects
+}
+
+fn book_cover_scene() -> Vec<Arc<dyn Hittable>> {
+    let mut world_elements: Vec<Arc<dyn Hittable>> = vec![];
+    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
+
+    let _checker = Arc::new(Checkerboard {
+        albedo_odd: Arc::new(SolidColor::new(0.2, 0.4, 0.6)),
+        albedo_even: Arc::new(SolidColor::new(0.6, 0.6, 0.2)),
+    });
+
+    // let noise = Arc::new(MarbleNoise {
+    //     perlin: Perlin::new(&mut rng),
+    //     scale: 4.,
+    //     depth: 5,
+    // });
+
+    // let ground_mat = Arc::new(Lambertian { albedo: noise });
+    // world_elements.push(Arc::new(Sphere {
+    //     center: Vec3::new(0.0, -1000.0, 0.0),
+    //     radius: 1000.0,
+    //     material: ground_mat,
+    // }));
+    world_elements.push(flat_earth(Vec2::new(-20., -20.), Vec2::new(20., 20.), 0.));
+
+    let material_distribution = Uniform::from(0..4);
+
+    let uniform_dist = Uniform::from(0.0..1.0);
+    let metal_dist = Uniform::from(0.5..1.0);
+    let emissive_dist = Uniform::from(0.5..4.0);
+    let metal_roughness_dist = Uniform::from(0.0..0.5);
+    let position_dist = Uniform::from(-0.9..0.9);
+    let glass_mat: Arc<dyn Material> = Arc::new(Dielectric { ior: 1.5 });
+
+    let big_sphere_pos_1 = Vec3::new(0.0, 1.0, 0.0);
+    let big_sphere_pos_2 = Vec3::new(-4.0, 1.0, 0.0);
+    let big_sphere_pos_3 = Vec3::new(4.0, 1.0, 0.0);
+    for x in -11..11 {
+        for y in -11..11 {
+            let center = Vec3::new(
+                x as f64 + position_dist.sample(&mut rng),
+                0.2,
+                y as f64 + position_dist.sample(&mut rng),
+            );
+            if (center - big_sphere_pos_1).norm() < 1.5 {
+                continue;
+            }
+            if (center - big_sphere_pos_2).norm() < 1.5 {
+                continue;
+            }
+            if (center - big_sphere_pos_3).norm() < 1.5 {
+                continue;
+            }
+            let selector = material_distribution.sample(&mut rng);
+            let material: Arc<dyn Material> = match selector {
+                0 => {
+                    let a1 = generate_vector(&uniform_dist, &mut rng);
+                    let a2 = generate_vector(&uniform_dist, &mut rng);
+                    let albedo = Arc::new(SolidColor::new(a1.x * a2.x, a1.y * a2.y, a1.z * a2.z));
+                    Arc::new(Lambertian { albedo })
+                }
+                1 => {
+                    let albedo = Arc::new(SolidColor {
+                        albedo: generate_vector(&metal_dist, &mut rng),
+                    });
+                    Arc::new(Metal {
+                        albedo,
+                        roughness: metal_roughness_dist.sample(&mut rng),
+                    })
+                }
+                2 => Arc::clone(&glass_mat),
+                3 => {
+                    let emissive = Arc::new(SolidColor {
+                        albedo: generate_vector(&emissive_dist, &mut rng),
+                    });
+                    Arc::new(DiffuseLight { emissive })
+                }
+                _ => panic!("Unreachable"),
+            };
+
+            world_elements.push(match selector {
+                0 => {
+                    let center_2 =
+                        center + Vec3::new(0.0, metal_roughness_dist.sample(&mut rng), 0.0);
+                    Arc::new(MovingSphere {
+                        center_begin: center,
+                        center_end: center_2,
+                        time_begin: 0.0,
+                        time_end: 1.0,
+                        radius: 0.2,
+                        material,
+                    })
+                }
+                1..=3 => Arc::new(Sphere {
+                    center,
+                    radius: 0.2,
+                    material,
+                }),
+                _ => panic!("Unreachable"),
+            });
+        }
+    }
+
+    let mat1: Arc<dyn Material> = Arc::new(Dielectric { ior: 1.5 });
+    world_elements.push(Arc::new(Sphere {
+        center: big_sphere_pos_1,
+        radius: 1.0,
+        material: Arc::clone(&mat1),
+    }));
+    world_elements.push(Arc::new(Sphere {
+        center: big_sphere_pos_1,
+        radius: -0.8,
+        material: mat1,
+    }));
+    world_elements.push(earth(big_sphere_pos_2, 1.0));
+    let mat2: Arc<dyn Material> = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.4, 0.2, 0.1)),
+    });
+    world_elements.push(Arc::new(Sphere {
+        center: big_sphere_pos_2,
+        radius: 1.0,
+        material: mat2,
+    }));
+    let mat3: Arc<dyn Material> = Arc::new(Metal {
+        albedo: Arc::new(SolidColor::new(0.7, 0.6, 0.5)),
+        roughness: 0.0,
+    });
+    world_elements.push(Arc::new(Sphere {
+        center: big_sphere_pos_3,
+        radius: 1.0,
+        material: mat3,
+    }));
+
+    world_elements.push(Arc::new(Sphere {
+        center: Vec3::new(0.0, 10.0, 0.0),
+        radius: 2.0,
+        material: Arc::new(DiffuseLight {
+            emissive: Arc::new(SolidColor::new(5.0, 5.0, 5.0)),
+        }),
+    }));
+
+    world_elements
+}
+
+fn cornell_box() -> Vec<Arc<dyn Hittable>> {
+    let mut objects: Vec<Arc<dyn Hittable>> = vec![];
+    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
+
+    let red = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.65, 0.05, 0.05)),
+    });
+    let white = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.73, 0.73, 0.73)),
+    });
+    let green = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.12, 0.45, 0.15)),
+    });
+
+    let light = Arc::new(DiffuseLight {
+        emissive: Arc::new(SolidColor::new(15., 15., 15.)),
+    });
+
+    // let metal_02 = Arc::new(Metal {
+    //     albedo: Arc::new(SolidColor::new(0.8, 0.8, 0.8)),
+    //     roughness: 0.2,
+    // });
+    // let metal_05 = Arc::new(Metal {
+    //     albedo: Arc::new(SolidColor::new(0.8, 0.8, 0.8)),
+    //     roughness: 0.5,
+    // });
+    // let metal_08 = Arc::new(Metal {
+    //     albedo: Arc::new(SolidColor::new(0.8, 0.8, 0.8)),
+    //     roughness: 0.8,
+    // });
+
+    cornell_walls(&mut objects, red, white.clone(), green);
+    // Light
+    objects.push(Arc::new(XzPlane {
+        min: Vec2::new(213., 227.),
+        max: Vec2::new(343., 342.),
+        k: 554.,
+        material: light.clone(),
+    }));
+    // Spheres
+    // objects.push(Arc::new(Sphere {
+    //     center: Vec3::new(139., 60., 284.),
+    //     radius: 60.,
+    //     material: metal_02.clone(),
+    // }));
+    // objects.push(Arc::new(Sphere {
+    //     center: Vec3::new(278., 60., 284.),
+    //     radius: 60.,
+    //     material: metal_05.clone(),
+    // }));
+    // objects.push(Arc::new(Sphere {
+    //     center: Vec3::new(417., 60., 284.),
+    //     radius: 60.,
+    //     material: metal_08.clone(),
+    // }));
+    // Cubes
+    let cube_mat = Mat4::new_rotation(Vec3::new(0., 1., 0.) * f64::to_radians(15.));
+    let cube_mat = cube_mat.append_translation(&Vec3::new(265., 0., 295.));
+
+    let cube = Arc::new(Cube::new(
+        Vec3::new(-82.5 * 0., 0., -82.5 * 0.),
+        Vec3::new(82.5 * 2., 330., 82.5 * 2.),
+        white.clone(),
+        &mut rng,
+    ));
+    objects.push(Arc::new(Transform::new(&cube_mat, cube)));
+
+    let cube_mat = Mat4::new_rotation(Vec3::new(0., 1., 0.) * f64::to_radians(-18.));
+    let cube_mat = cube_mat.append_translation(&Vec3::new(130., 0., 65.));
+
+    let cube = Arc::new(Cube::new(
+        Vec3::new(0., 0., 0.),
+        Vec3::new(165., 165., 165.),
+        white.clone(),
+        &mut rng,
+    ));
+    objects.push(Arc::new(Transform::new(&cube_mat, cube)));
+
+    objects
+}
+
+// The box's walls, opened on the -Z side.
+fn cornell_walls(
+    objects: &mut Vec<Arc<dyn Hittable>>,
+    red: Arc<Lambertian>,
+    white: Arc<Lambertian>,
+    green: Arc<Lambertian>,
+) {
+    // Left and right
+    objects.push(Arc::new(YzPlane {
+        min: Vec2::new(0., 0.),
+        max: Vec2::new(555., 555.),
+        k: 555.,
+        material: green,
+    }));
+    objects.push(Arc::new(YzPlane {
+        min: Vec2::new(0., 0.),
+        max: Vec2::new(555., 555.),
+        k: 0.,
+        material: red,
+    }));
+    // Top and bottom
+    objects.push(Arc::new(XzPlane {
+        min: Vec2::new(0., 0.),
+        max: Vec2::new(555., 555.),
+        k: 0.,
+        material: white.clone(),
+    }));
+    objects.push(Arc::new(XzPlane {
+        min: Vec2::new(0., 0.),
+        max: Vec2::new(555., 555.),
+        k: 555.,
+        material: white.clone(),
+    }));
+    // Back
+    objects.push(Arc::new(XyPlane {
+        min: Vec2::new(0., 0.),
+        max: Vec2::new(555., 555.),
+        k: 555.,
+        material: white.clone(),
+    }));
+}
+
+// The Cornell box from The Next Week with its boxes made of smoke.
+fn cornell_smoke() -> Vec<Arc<dyn Hittable>> {
+    let mut objects: Vec<Arc<dyn Hittable>> = vec![];
+    let mut rng = SmallRng::seed_from_u64(0xDEADBEEF);
+
+    let red = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.65, 0.05, 0.05)),
+    });
+    let white = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.73, 0.73, 0.73)),
+    });
+    let green = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColor::new(0.12, 0.45, 0.15)),
+    });
+    let light = Arc::new(DiffuseLight {
+        emissive: Arc::new(SolidColor::new(7., 7., 7.)),
+    });
+
+    cornell_walls(&mut objects, red, white.clone(), green);
+    // A larger, dimmer light than the regular box's.
+    objects.push(Arc::new(XzPlane {
+        min: Vec2::new(113., 127.),
+        max: Vec2::new(443., 432.),
+        k: 554.,
+        material: light,
+    }));
+
+    let cube_mat = Mat4::new_rotation(Vec3::new(0., 1., 0.) * f64::to_radians(15.));
+    let cube_mat = cube_mat.append_translation(&Vec3::new(265., 0., 295.));
+    let cube = Arc::new(Cube::new(
+        Vec3::new(0., 0., 0.),
+        Vec3::new(165., 330., 165.),
+        white.clone(),
+        &mut rng,
+    ));
+    objects.push(Arc::new(ConstantMedium::new(
+        Arc::new(Transform::new(&cube_mat, cube)),
+        0.01,
+        Arc::new(SolidColor::new(0., 0., 0.)),
+        None,
+    )));
+
+    let cube_mat = Mat4::new_rotation(Vec3::new(0., 1., 0.) * f64::to_radians(-18.));
+    let cube_mat = cube_mat.append_translation(&Vec3::new(130., 0., 65.));
+    let cube = Arc::new(Cube::new(
+        Vec3::new(0., 0., 0.),
+        Vec3::new(165., 165., 165.),
+        white,
+        &mut rng,
+    ));
+    objects.push(Arc::new(ConstantMedium::new(
+        Arc::new(Transform::new(&cube_mat, cube)),
+        0.01,
+        Arc::new(SolidColor::new(1., 1., 1.)),
+        None,
+    )));
+
+    objects
+}
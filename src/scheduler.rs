@@ -1,11 +1,9 @@
-use crate::hittable::BvhNode;
 use crate::math::Vec3;
 use crate::ray_color;
-use crate::render::{Camera, RenderTile, Subregion};
+use crate::render::{take_ray_count, Camera, RenderTile, Subregion, World};
 use rand::rngs::SmallRng;
 use rand::SeedableRng;
 use rand_distr::{Distribution, Uniform};
-use std::sync::Arc;
 use std::thread::JoinHandle;
 
 // Making a struct is a forward thought.
@@ -15,8 +13,9 @@ use std::thread::JoinHandle;
 pub struct Scheduler {}
 
 impl Scheduler {
+    #[allow(clippy::too_many_arguments)]
     fn spawn_thread(
-        world: &Arc<BvhNode>,
+        world: &World,
         cam: &Camera,
         tid: usize,
         num_threads: usize,
@@ -26,7 +25,7 @@ impl Scheduler {
         max_depth: u16,
     ) -> JoinHandle<RenderTile> {
         let local_world = world.clone();
-        let local_camera = cam.clone();
+        let local_camera = *cam;
         let subregion = Subregion::slice_vertically(tid, num_threads, render_width, render_height);
         std::thread::spawn(move || {
             let mut worker = RenderTile::new(subregion, local_world, local_camera);
@@ -58,30 +57,27 @@ impl Scheduler {
                         let t = 1.0 - (jitter_y + uv_y_offset) / height_minus_one;
 
                         let ray = worker.camera.get_ray(s, t, &mut rng);
-                        sum += ray_color(
-                            ray,
-                            &Vec3::zeros(),
-                            worker.scene.as_ref(),
-                            max_depth,
-                            &mut rng,
-                        );
+                        sum += ray_color(ray, &worker.scene, max_depth, 1.0, &mut rng);
                     }
                     worker.buffer[tile_y_offset + x] = sum;
                 }
             }
+            worker.rays = take_ray_count();
             worker
         })
     }
 
+    // Returns the average of every pixel's samples and the number of rays
+    // traced.
     pub fn run_threaded(
-        world: &Arc<BvhNode>,
+        world: &World,
         cam: &Camera,
         num_iterations: usize,
         num_threads: usize,
         render_width: usize,
         render_height: usize,
         max_depth: u16,
-    ) -> Vec<Vec3> {
+    ) -> (Vec<Vec3>, u64) {
         let mut thread_handles = vec![];
 
         for tid in 0..num_threads {
@@ -98,10 +94,12 @@ impl Scheduler {
         }
 
         // Untile data and blit to the final buffer.
-        let mut final_buffer = vec![Vec3::zeros(); (render_height * render_width) as usize];
+        let mut final_buffer = vec![Vec3::zeros(); render_height * render_width];
+        let mut rays = 0;
         for tid in thread_handles {
             match tid.join() {
                 Ok(worker) => {
+                    rays += worker.rays;
                     for y in 0..worker.region.height {
                         let y_offset = worker.region.y + y;
                         let x_offset = y * worker.region.width;
@@ -109,13 +107,14 @@ impl Scheduler {
                         for x in 0..worker.region.width {
                             let in_index = x_offset + x;
                             let out_index = out_buffer_y_offset + x;
-                            final_buffer[out_index] = worker.buffer[in_index];
+                            final_buffer[out_index] =
+                                worker.buffer[in_index] / num_iterations as f64;
                         }
                     }
                 }
                 Err(err) => std::panic::panic_any(err),
             };
         }
-        final_buffer
+        (final_buffer, rays)
     }
 }
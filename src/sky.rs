@@ -0,0 +1,234 @@
+// Preetham, Shirley and Smits' analytic daylight model ("A Practical Analytic
+// Model for Daylight", 1999): the sky's luminance and chromaticity follow
+// Perez's distribution, from the sun's position and the air's turbidity.
+use crate::colors::luminance;
+use crate::environment::Environment;
+use crate::math::*;
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+use std::f64::consts::{PI, TAU};
+
+// Radiances are in cd/m², scaled so daylight on a white surface is about 1.
+const LUMINANCE_SCALE: f64 = 3e-5;
+// Sunlight before going through the atmosphere, in lux.
+const EXTRATERRESTRIAL_ILLUMINANCE: f64 = 128_000.0;
+// Wavelengths, in micrometers, standing for the red, green and blue channels.
+const WAVELENGTHS: [f64; 3] = [0.65, 0.55, 0.45];
+
+// The five coefficients of the Perez distribution, and its value.
+struct Perez([f64; 5]);
+
+impl Perez {
+    fn new(turbidity: f64, slopes: [f64; 5], offsets: [f64; 5]) -> Perez {
+        let mut coefficients = [0.0; 5];
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            *coefficient = slopes[i] * turbidity + offsets[i];
+        }
+        Perez(coefficients)
+    }
+
+    // theta is the angle from the zenith, gamma the one from the sun.
+    fn value(&self, theta: f64, gamma: f64) -> f64 {
+        let [a, b, c, d, e] = self.0;
+        (1.0 + a * (b / theta.cos()).exp())
+            * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+    }
+}
+
+// The sky above the horizon, black below it as scenes bring their own
+// ground. The sun is a disc of angular_diameter radians, as bright overall
+// whatever its size, so bigger suns only give softer shadows.
+pub struct PreethamSky {
+    sun_direction: Vec3,
+    cos_sun_radius: f64,
+    sun_radiance: Vec3,
+    perez: [Perez; 3],
+    // Luminance and chromaticity at the zenith, divided by the Perez
+    // distribution there.
+    zenith: [f64; 3],
+    intensity: f64,
+    // Probability of sampling the sun rather than the rest of the sky.
+    sun_probability: f64,
+    power: f64,
+}
+
+impl PreethamSky {
+    // sun_direction points towards the sun, above the horizon. The model
+    // holds for turbidities from about 2 (clear) to 10 (hazy).
+    pub fn new(
+        sun_direction: &Vec3,
+        turbidity: f64,
+        angular_diameter: f64,
+        intensity: f64,
+    ) -> PreethamSky {
+        let sun_direction = sun_direction.normalize();
+        assert!(sun_direction.y > 0.0, "The sun must be above the horizon");
+        let t = turbidity;
+        let theta_sun = sun_direction.y.acos();
+
+        let perez = [
+            Perez::new(
+                t,
+                [0.1787, -0.3554, -0.0227, 0.1206, -0.0670],
+                [-1.4630, 0.4275, 5.3251, -2.5771, 0.3703],
+            ),
+            Perez::new(
+                t,
+                [-0.0193, -0.0665, -0.0004, -0.0641, -0.0033],
+                [-0.2592, 0.0008, 0.2125, -0.8989, 0.0452],
+            ),
+            Perez::new(
+                t,
+                [-0.0167, -0.0950, -0.0079, -0.0441, -0.0109],
+                [-0.2608, 0.0092, 0.2102, -1.6537, 0.0529],
+            ),
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        // In kcd/m².
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_chromaticity = |matrix: [[f64; 4]; 3]| {
+            let thetas = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+            let turbidities = [t * t, t, 1.0];
+            let mut value = 0.0;
+            for (row, turbidity_power) in matrix.iter().zip(turbidities.iter()) {
+                for (coefficient, theta_power) in row.iter().zip(thetas.iter()) {
+                    value += turbidity_power * coefficient * theta_power;
+                }
+            }
+            value
+        };
+        let zenith_x = zenith_chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = zenith_chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+        let zenith = [
+            zenith_luminance * 1000.0 / perez[0].value(0.0, theta_sun),
+            zenith_x / perez[1].value(0.0, theta_sun),
+            zenith_y / perez[2].value(0.0, theta_sun),
+        ];
+
+        // The sun is dimmed and reddened by Rayleigh scattering and aerosols
+        // over the mass of air it goes through (Kasten and Young's formula).
+        let elevation = 90.0 - theta_sun.to_degrees();
+        let air_mass = 1.0 / (theta_sun.cos() + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+        let aerosols = 0.04608 * t - 0.04586;
+        let transmittance = Vec3::from_iterator(WAVELENGTHS.iter().map(|&wavelength| {
+            let rayleigh = 0.008735 * wavelength.powf(-4.08);
+            let mie = aerosols * wavelength.powf(-1.3);
+            (-air_mass * (rayleigh + mie)).exp()
+        }));
+        let sun_radius = angular_diameter / 2.0;
+        let cos_sun_radius = sun_radius.cos();
+        let sun_solid_angle = TAU * (1.0 - cos_sun_radius);
+        let sun_irradiance = transmittance * EXTRATERRESTRIAL_ILLUMINANCE * LUMINANCE_SCALE;
+
+        let mut sky = PreethamSky {
+            sun_direction,
+            cos_sun_radius,
+            sun_radiance: sun_irradiance / sun_solid_angle,
+            perez,
+            zenith,
+            intensity,
+            sun_probability: 0.0,
+            power: 0.0,
+        };
+
+        // The sky's power is summed over a grid of the upper hemisphere.
+        let (rings, sectors) = (32, 64);
+        let mut sky_power = 0.0;
+        for ring in 0..rings {
+            let theta = (ring as f64 + 0.5) / rings as f64 * PI / 2.0;
+            let solid_angle = theta.sin() * (PI / 2.0 / rings as f64) * (TAU / sectors as f64);
+            for sector in 0..sectors {
+                let phi = (sector as f64 + 0.5) / sectors as f64 * TAU;
+                let direction = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                sky_power += luminance(&sky.sky_radiance(&direction)) * solid_angle;
+            }
+        }
+        let sun_power = luminance(&sun_irradiance);
+        sky.sun_probability = sun_power / (sun_power + sky_power);
+        sky.power = (sun_power + sky_power) * intensity;
+        sky
+    }
+
+    // Without the sun, for a direction above the horizon.
+    fn sky_radiance(&self, direction: &Vec3) -> Vec3 {
+        let direction = direction.normalize();
+        let theta = direction.y.clamp(0.0, 1.0).acos();
+        let gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = [0, 1, 2]
+            .map(|i| self.zenith[i] * self.perez[i].value(theta, gamma))
+            .map(|value| value.max(0.0));
+        if y <= 0.0 {
+            return Vec3::zeros();
+        }
+        // From xyY to CIE XYZ, then to linear sRGB.
+        let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        let rgb = Vec3::new(
+            3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+            -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+            0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+        );
+        rgb.map(|channel| channel.max(0.0)) * LUMINANCE_SCALE
+    }
+
+    fn in_sun(&self, direction: &Vec3) -> bool {
+        direction.normalize().dot(&self.sun_direction) >= self.cos_sun_radius
+    }
+}
+
+impl Environment for PreethamSky {
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        if direction.y <= 0.0 {
+            return Vec3::zeros();
+        }
+        let mut radiance = self.sky_radiance(direction);
+        if self.in_sun(direction) {
+            radiance += self.sun_radiance;
+        }
+        radiance * self.intensity
+    }
+
+    // The sun's cone uniformly, or the upper hemisphere uniformly.
+    fn pdf_value(&self, direction: &Vec3) -> f64 {
+        let mut value = 0.0;
+        if self.in_sun(direction) {
+            value += self.sun_probability / (TAU * (1.0 - self.cos_sun_radius));
+        }
+        if direction.y > 0.0 {
+            value += (1.0 - self.sun_probability) / TAU;
+        }
+        value
+    }
+
+    fn random(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let distribution = Uniform::from(0.0..1.0);
+        let phi = TAU * distribution.sample(rng);
+        if distribution.sample(rng) < self.sun_probability {
+            let cos_theta = 1.0 + distribution.sample(rng) * (self.cos_sun_radius - 1.0);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let (tangent, bitangent) = orthonormal_basis(&self.sun_direction);
+            return self.sun_direction * cos_theta
+                + tangent * (sin_theta * phi.cos())
+                + bitangent * (sin_theta * phi.sin());
+        }
+        let y: f64 = distribution.sample(rng);
+        let radius = (1.0 - y * y).sqrt();
+        Vec3::new(radius * phi.cos(), y, radius * phi.sin())
+    }
+
+    fn power(&self) -> f64 {
+        self.power
+    }
+}
@@ -40,6 +40,12 @@ pub struct ImageTexture {
     texture: RgbImage,
 }
 
+// Another texture multiplied by a color, like glTF's factors.
+pub struct ScaledTexture {
+    pub texture: Arc<dyn Texture>,
+    pub scale: Vec3,
+}
+
 impl SolidColor {
     pub fn new(r: f64, g: f64, b: f64) -> SolidColor {
         SolidColor {
@@ -76,13 +82,13 @@ impl Texture for Noise {
 
 impl Texture for TurbulentNoise {
     fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
-        Vec3::from_element(pertubation(&self.perlin, self.depth, &p))
+        Vec3::from_element(pertubation(&self.perlin, self.depth, &(p * self.scale)))
     }
 }
 
 impl Texture for MarbleNoise {
     fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
-        let noise_value = pertubation(&self.perlin, self.depth, &p);
+        let noise_value = pertubation(&self.perlin, self.depth, p);
         let v = (1.0 + f64::sin(10.0 * noise_value + self.scale * p.z)) / 2.0;
         Vec3::from_element(v)
     }
@@ -90,13 +96,23 @@ impl Texture for MarbleNoise {
 
 impl ImageTexture {
     pub fn from_path(url: &str) -> ImageTexture {
-        match image::open(url) {
-            Ok(img) => ImageTexture {
-                texture: img.to_rgb8(),
-            },
+        match ImageTexture::open(url) {
+            Ok(texture) => texture,
             Err(err) => panic!("Couldn't open the picture at {} ({})", url, err),
         }
     }
+
+    // Non-panicking version for loaders that report their own errors.
+    pub fn open(url: &str) -> Result<ImageTexture, image::ImageError> {
+        let img = image::open(url)?;
+        Ok(ImageTexture::from_image(&img))
+    }
+
+    pub fn from_image(img: &image::DynamicImage) -> ImageTexture {
+        ImageTexture {
+            texture: img.to_rgb8(),
+        }
+    }
 }
 
 impl Texture for ImageTexture {
@@ -119,8 +135,13 @@ impl Texture for ImageTexture {
             (pixel[1] as f64 / 255.0).powf(2.2),
             (pixel[2] as f64 / 255.0).powf(2.2),
         );
-        let pixel_vec = Vec3::new(r, g, b);
-        pixel_vec
+        Vec3::new(r, g, b)
+    }
+}
+
+impl Texture for ScaledTexture {
+    fn value(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        self.texture.value(u, v, p).component_mul(&self.scale)
     }
 }
 
@@ -0,0 +1,118 @@
+use crate::colors;
+use crate::math::Vec3;
+
+// Brings linear radiances into the displayable [0; 1] range, still linear.
+pub trait ToneMapper: Sync + Send {
+    fn map(&self, color: &Vec3) -> Vec3;
+}
+
+// Everything past 1 is lost, like the writers used to do.
+pub struct Clamp;
+
+// Reinhard et al.'s L / (1 + L), on the luminance to keep the hues.
+pub struct Reinhard;
+
+// Narkowicz's fit of the ACES filmic curve, with a toe and a soft shoulder.
+pub struct AcesFilmic;
+
+impl ToneMapper for Clamp {
+    fn map(&self, color: &Vec3) -> Vec3 {
+        color.map(|channel| channel.clamp(0.0, 1.0))
+    }
+}
+
+impl ToneMapper for Reinhard {
+    fn map(&self, color: &Vec3) -> Vec3 {
+        let luminance = colors::luminance(color);
+        if luminance <= 0.0 {
+            return Vec3::zeros();
+        }
+        let scale = 1.0 / (1.0 + luminance);
+        (color * scale).map(|channel| channel.clamp(0.0, 1.0))
+    }
+}
+
+impl ToneMapper for AcesFilmic {
+    fn map(&self, color: &Vec3) -> Vec3 {
+        color.map(|x| {
+            let x = x.max(0.0);
+            ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+        })
+    }
+}
+
+pub fn find_tone_mapper(name: &str) -> Option<Box<dyn ToneMapper>> {
+    match name.to_lowercase().as_str() {
+        "clamp" => Some(Box::new(Clamp)),
+        "reinhard" => Some(Box::new(Reinhard)),
+        "aces" => Some(Box::new(AcesFilmic)),
+        _ => None,
+    }
+}
+
+// Turns the rendered radiances into display-ready sRGB values in [0; 1]:
+// scaled by the exposure, in EV (stops), tone mapped then encoded.
+pub struct PostProcess {
+    pub exposure: f64,
+    pub tone_mapper: Box<dyn ToneMapper>,
+}
+
+impl PostProcess {
+    pub fn apply(&self, data: &[Vec3]) -> Vec<Vec3> {
+        let scale = self.exposure.exp2();
+        data.iter()
+            .map(|color| colors::linear_to_srgb(&self.tone_mapper.map(&(color * scale))))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(found: &Vec3, expected: &Vec3) {
+        assert!((found - expected).norm() < 1e-4, "{:?}", found);
+    }
+
+    fn grey(value: f64) -> Vec3 {
+        Vec3::new(value, value, value)
+    }
+
+    #[test]
+    fn tone_mappers_map_known_values() {
+        assert_close(
+            &Clamp.map(&Vec3::new(-1.0, 0.5, 3.0)),
+            &Vec3::new(0.0, 0.5, 1.0),
+        );
+
+        assert_close(&Reinhard.map(&grey(1.0)), &grey(0.5));
+        assert_close(&Reinhard.map(&grey(3.0)), &grey(0.75));
+        assert_close(&Reinhard.map(&grey(-1.0)), &grey(0.0));
+        // Hues are kept, the luminance of pure red being 0.2126.
+        let red = Reinhard.map(&Vec3::new(1.0, 0.0, 0.0));
+        assert_close(&red, &Vec3::new(1.0 / 1.2126, 0.0, 0.0));
+
+        assert_close(&AcesFilmic.map(&grey(0.0)), &grey(0.0));
+        assert_close(&AcesFilmic.map(&grey(1.0)), &grey(2.54 / 3.16));
+        assert_close(&AcesFilmic.map(&grey(1e6)), &grey(1.0));
+    }
+
+    #[test]
+    fn exposure_scales_before_tone_mapping() {
+        let post_process = PostProcess {
+            exposure: 1.0,
+            tone_mapper: find_tone_mapper("Reinhard").unwrap(),
+        };
+        // Doubled to 1 then mapped to 0.5, encoded in sRGB.
+        let mapped = post_process.apply(&[grey(0.5), grey(0.0)]);
+        assert_close(&mapped[0], &grey(0.735357));
+        assert_close(&mapped[1], &grey(0.0));
+
+        let post_process = PostProcess {
+            exposure: -2.0,
+            tone_mapper: find_tone_mapper("clamp").unwrap(),
+        };
+        assert_close(&post_process.apply(&[grey(4.0)])[0], &grey(1.0));
+        assert!(find_tone_mapper("filmic").is_none());
+    }
+}
@@ -0,0 +1,351 @@
+// Dense voxel grids, for the densities exported by simulations.
+//
+// Text grids start with a `voxels NX NY NZ` header, followed by the
+// NX * NY * NZ values separated by whitespace. `#` starts a comment running to
+// the end of the line. Raw grids (`.raw`) are headerless little-endian 32-bit
+// floats, their resolution being given by the scene.
+//
+// Both store the values with x varying fastest, then y, then z. Values are
+// densities, relative to the volume's `density`, and can't be negative.
+use crate::hittable::{delta_tracking, Hittable};
+use crate::material::Material;
+use crate::math::*;
+use crate::{HitRecord, Ray};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum VoxelError {
+    Io(PathBuf, std::io::Error),
+    Parse { path: PathBuf, message: String },
+}
+
+impl fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxelError::Io(path, err) => write!(f, "couldn't read {} ({})", path.display(), err),
+            VoxelError::Parse { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for VoxelError {}
+
+pub struct VoxelGrid {
+    pub resolution: [usize; 3],
+    pub values: Vec<f64>,
+}
+
+impl VoxelGrid {
+    fn at(&self, x: isize, y: isize, z: isize) -> f64 {
+        let clamp = |coordinate: isize, axis: usize| {
+            coordinate.clamp(0, self.resolution[axis] as isize - 1) as usize
+        };
+        let (x, y, z) = (clamp(x, 0), clamp(y, 1), clamp(z, 2));
+        self.values[x + self.resolution[0] * (y + self.resolution[1] * z)]
+    }
+
+    pub fn max_value(&self) -> f64 {
+        self.values.iter().cloned().fold(0.0, f64::max)
+    }
+
+    // p is in voxel units, the grid spanning [0; resolution] with the values
+    // at the voxels' centers. Past the outer centers, the edge values are
+    // extended.
+    pub fn lookup(&self, p: &Vec3) -> f64 {
+        let p = p - Vec3::from_element(0.5);
+        let (x, y, z) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let mut corners = [[[0.0; 2]; 2]; 2];
+        for (i, plane) in corners.iter_mut().enumerate() {
+            for (j, row) in plane.iter_mut().enumerate() {
+                for (k, corner) in row.iter_mut().enumerate() {
+                    *corner = self.at(
+                        x as isize + i as isize,
+                        y as isize + j as isize,
+                        z as isize + k as isize,
+                    );
+                }
+            }
+        }
+        trilinear_interpolation(corners, p.x - x, p.y - y, p.z - z)
+    }
+}
+
+pub fn load_voxel_grid(
+    path: &Path,
+    resolution: Option<[usize; 3]>,
+) -> Result<VoxelGrid, VoxelError> {
+    let bytes = std::fs::read(path).map_err(|err| VoxelError::Io(path.to_path_buf(), err))?;
+    parse_voxel_grid(path, bytes, resolution)
+}
+
+// The path tells raw grids apart and names the file in errors, it isn't read.
+fn parse_voxel_grid(
+    path: &Path,
+    bytes: Vec<u8>,
+    resolution: Option<[usize; 3]>,
+) -> Result<VoxelGrid, VoxelError> {
+    let parse_error = |message: String| VoxelError::Parse {
+        path: path.to_path_buf(),
+        message,
+    };
+    let is_raw = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("raw"));
+
+    let (resolution, values) = if is_raw {
+        let resolution = resolution
+            .ok_or_else(|| parse_error("raw grids need a resolution in the scene".to_string()))?;
+        if !bytes.len().is_multiple_of(4) {
+            return Err(parse_error(format!(
+                "{} bytes isn't a whole number of 32-bit floats",
+                bytes.len()
+            )));
+        }
+        let values = bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as f64)
+            .collect();
+        (resolution, values)
+    } else {
+        if resolution.is_some() {
+            return Err(parse_error(
+                "text grids give their resolution in their header".to_string(),
+            ));
+        }
+        let text = String::from_utf8(bytes)
+            .map_err(|_| parse_error("text grids must be UTF-8".to_string()))?;
+        let mut words = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(str::split_whitespace);
+        if words.next() != Some("voxels") {
+            return Err(parse_error(
+                "expected a `voxels NX NY NZ` header".to_string(),
+            ));
+        }
+        let mut resolution = [0; 3];
+        for size in resolution.iter_mut() {
+            *size = match words.next().map(str::parse) {
+                Some(Ok(size)) => size,
+                _ => {
+                    return Err(parse_error(
+                        "expected a `voxels NX NY NZ` header".to_string(),
+                    ))
+                }
+            };
+        }
+        let values = words
+            .map(|word| {
+                word.parse::<f64>()
+                    .map_err(|_| parse_error(format!("`{}` isn't a number", word)))
+            })
+            .collect::<Result<Vec<f64>, VoxelError>>()?;
+        (resolution, values)
+    };
+
+    if resolution.contains(&0) {
+        return Err(parse_error("the resolution can't be zero".to_string()));
+    }
+    let expected = resolution[0]
+        .checked_mul(resolution[1])
+        .and_then(|count| count.checked_mul(resolution[2]))
+        .ok_or_else(|| {
+            parse_error(format!(
+                "a {}x{}x{} grid is too large",
+                resolution[0], resolution[1], resolution[2]
+            ))
+        })?;
+    if values.len() != expected {
+        return Err(parse_error(format!(
+            "expected {} values for a {}x{}x{} grid, found {}",
+            expected,
+            resolution[0],
+            resolution[1],
+            resolution[2],
+            values.len()
+        )));
+    }
+    if let Some(value) = values
+        .iter()
+        .find(|value| !value.is_finite() || **value < 0.0)
+    {
+        return Err(parse_error(format!(
+            "densities must be non-negative numbers, found {}",
+            value
+        )));
+    }
+    Ok(VoxelGrid { resolution, values })
+}
+
+// A voxel grid stretched over a box, rendered with delta tracking against the
+// grid's highest density. Wrap it in a Transform to rotate it.
+pub struct VoxelVolume {
+    grid: VoxelGrid,
+    bbox: AABB,
+    density: f64,
+    majorant: f64,
+    phase_function: Arc<dyn Material>,
+}
+
+impl VoxelVolume {
+    pub fn new(
+        grid: VoxelGrid,
+        bbox: AABB,
+        density: f64,
+        phase_function: Arc<dyn Material>,
+    ) -> VoxelVolume {
+        assert!(
+            (bbox.max - bbox.min).min() > 0.0,
+            "A voxel volume's box must have a volume"
+        );
+        VoxelVolume {
+            majorant: density * grid.max_value(),
+            grid,
+            bbox,
+            density,
+            phase_function,
+        }
+    }
+
+    fn density_ratio(&self, p: &Vec3) -> f64 {
+        let resolution = Vec3::new(
+            self.grid.resolution[0] as f64,
+            self.grid.resolution[1] as f64,
+            self.grid.resolution[2] as f64,
+        );
+        let grid_position = (p - self.bbox.min)
+            .component_div(&(self.bbox.max - self.bbox.min))
+            .component_mul(&resolution);
+        self.density * self.grid.lookup(&grid_position) / self.majorant
+    }
+}
+
+impl Hittable for VoxelVolume {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        if self.majorant <= 0.0 {
+            return None;
+        }
+        let (t_entry, t_exit) = self.bbox.hit_interval(ray, t_min.max(0.0), t_max)?;
+        delta_tracking(
+            ray,
+            t_entry,
+            t_exit,
+            self.majorant,
+            |p| self.density_ratio(p),
+            self.phase_function.as_ref(),
+        )
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
+        Some(self.bbox.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(name: &str, bytes: &[u8], resolution: Option<[usize; 3]>) -> String {
+        match parse_voxel_grid(Path::new(name), bytes.to_vec(), resolution) {
+            Err(VoxelError::Parse { message, .. }) => message,
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("the grid loaded"),
+        }
+    }
+
+    #[test]
+    fn loads_text_and_raw_grids() {
+        let text = b"voxels 2 1 2 # x, y and z\n0 1\n2 0.5\n";
+        let grid = parse_voxel_grid(Path::new("grid.vox"), text.to_vec(), None).unwrap();
+        assert_eq!(grid.resolution, [2, 1, 2]);
+        assert_eq!(grid.values, vec![0.0, 1.0, 2.0, 0.5]);
+        assert_eq!(grid.max_value(), 2.0);
+
+        let raw: Vec<u8> = [0.0f32, 1.0, 2.0, 0.5]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let grid = parse_voxel_grid(Path::new("grid.RAW"), raw, Some([2, 1, 2])).unwrap();
+        assert_eq!(grid.values, vec![0.0, 1.0, 2.0, 0.5]);
+    }
+
+    #[test]
+    fn lookups_interpolate_between_voxel_centers() {
+        let grid = VoxelGrid {
+            resolution: [2, 1, 1],
+            values: vec![0.0, 1.0],
+        };
+        assert_eq!(grid.lookup(&Vec3::new(0.5, 0.5, 0.5)), 0.0);
+        assert_eq!(grid.lookup(&Vec3::new(1.0, 0.5, 0.5)), 0.5);
+        assert_eq!(grid.lookup(&Vec3::new(1.5, 0.5, 0.5)), 1.0);
+        // Edge values extend past the outer centers.
+        assert_eq!(grid.lookup(&Vec3::new(2.0, 0.0, 1.0)), 1.0);
+    }
+
+    // File name, contents, resolution given by the scene and error.
+    type Case = (
+        &'static str,
+        &'static [u8],
+        Option<[usize; 3]>,
+        &'static str,
+    );
+
+    #[test]
+    fn reports_malformed_grids() {
+        let cases: [Case; 8] = [
+            (
+                "grid.vox",
+                b"grid 1 1 1\n0",
+                None,
+                "expected a `voxels NX NY NZ` header",
+            ),
+            (
+                "grid.vox",
+                b"voxels 1 1\n",
+                None,
+                "expected a `voxels NX NY NZ` header",
+            ),
+            (
+                "grid.vox",
+                b"voxels 1 0 1\n",
+                None,
+                "the resolution can't be zero",
+            ),
+            (
+                "grid.vox",
+                b"voxels 4294967296 4294967296 1\n0",
+                None,
+                "a 4294967296x4294967296x1 grid is too large",
+            ),
+            (
+                "grid.vox",
+                b"voxels 2 1 1\n0",
+                None,
+                "expected 2 values for a 2x1x1 grid, found 1",
+            ),
+            (
+                "grid.vox",
+                b"voxels 1 1 1\n-1",
+                None,
+                "densities must be non-negative numbers, found -1",
+            ),
+            (
+                "grid.raw",
+                &[0; 4],
+                None,
+                "raw grids need a resolution in the scene",
+            ),
+            (
+                "grid.raw",
+                &[0; 6],
+                Some([1, 1, 1]),
+                "6 bytes isn't a whole number of 32-bit floats",
+            ),
+        ];
+        for (name, bytes, resolution, message) in &cases {
+            assert_eq!(parse_error(name, bytes, *resolution), *message);
+        }
+    }
+}
@@ -1,37 +1,92 @@
-// TODO trait?
 use crate::colors;
 use crate::math::Vec3;
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::hdr::HdrEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::error::{LimitError, LimitErrorKind, ParameterError, ParameterErrorKind};
 use image::png::PngEncoder;
-use image::ColorType;
+use image::{ColorType, ImageError, ImageResult, Rgb};
 use std::convert::TryInto;
-use std::io::Write;
+use std::io::{self, Cursor, Write};
+use std::path::Path;
 
+// Writers get display-ready pixels, sRGB encoded in [0; 1], from PostProcess.
+// HDR formats get the rendered radiances as they are instead, linear and
+// unbounded, for compositing.
 pub trait ImageWriter {
-    // TODO Extract linear → sRGB conversion out of the interface
     fn write_to(
         &self,
         out: &mut dyn Write,
         data: &[Vec3],
         width: usize,
         height: usize,
-        num_samples: u32,
-    );
+    ) -> ImageResult<()>;
+
+    fn is_linear(&self) -> bool {
+        false
+    }
 }
 
-pub struct PPMWriter;
+pub struct WriterOptions {
+    // OpenEXR channels are halves unless this is set.
+    pub exr_float: bool,
+    // From 1 to 100.
+    pub jpeg_quality: u8,
+}
 
-impl PPMWriter {
-    pub fn write_header(out: &mut dyn Write, width: usize, height: usize) {
-        writeln!(out, "P3 {} {}\n255", width, height).unwrap();
+// The pixels as 8-bit RGB triplets, for the encoders.
+fn to_rgb8(data: &[Vec3]) -> Vec<u8> {
+    let mut encodable_data = Vec::with_capacity(data.len() * 3);
+    for c in data {
+        let (r, g, b) = colors::downscale_to_8bit(c);
+        encodable_data.push(r);
+        encodable_data.push(g);
+        encodable_data.push(b);
     }
+    encodable_data
+}
 
-    pub fn write_color(out: &mut dyn Write, color: &Vec3, num_samples: u32) {
-        let average = color / (num_samples as f64);
+// The encoders take 32-bit dimensions.
+fn encoder_dimensions(width: usize, height: usize) -> ImageResult<(u32, u32)> {
+    match (width.try_into(), height.try_into()) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        ))),
+    }
+}
 
-        let srgb = colors::linear_to_srgb(&average);
-        let (r, g, b) = colors::downscale_to_8bit(&srgb);
+// The pixels have to fill the picture, no more, no less, and empty pictures
+// can't be written.
+fn check_dimensions(data: &[Vec3], width: usize, height: usize) -> ImageResult<()> {
+    if width == 0 || height == 0 {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    if width.checked_mul(height) != Some(data.len()) {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+    Ok(())
+}
+
+// Plain text (P3) with one pixel per line, unless binary (P6) is set.
+pub struct PPMWriter {
+    pub binary: bool,
+}
+
+impl PPMWriter {
+    pub fn write_header(out: &mut dyn Write, width: usize, height: usize) -> io::Result<()> {
+        writeln!(out, "P3 {} {}\n255", width, height)
+    }
 
-        writeln!(out, "{} {} {}", r, g, b).unwrap();
+    pub fn write_color(out: &mut dyn Write, color: &Vec3) -> io::Result<()> {
+        let (r, g, b) = colors::downscale_to_8bit(color);
+
+        writeln!(out, "{} {} {}", r, g, b)
     }
 }
 
@@ -42,16 +97,24 @@ impl ImageWriter for PPMWriter {
         data: &[Vec3],
         width: usize,
         height: usize,
-        num_samples: u32,
-    ) {
-        assert_eq!(data.len(), width * height);
-        PPMWriter::write_header(out, width, height);
-        data.iter()
-            .for_each(|v| PPMWriter::write_color(out, v, num_samples));
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        if self.binary {
+            write!(out, "P6\n{} {}\n255\n", width, height)?;
+            out.write_all(&to_rgb8(data))?;
+            return Ok(());
+        }
+        PPMWriter::write_header(out, width, height)?;
+        for v in data {
+            PPMWriter::write_color(out, v)?;
+        }
+        Ok(())
     }
 }
 
-pub struct PNGWriter;
+pub struct PNGWriter {
+    pub sixteen_bits: bool,
+}
 
 impl ImageWriter for PNGWriter {
     fn write_to(
@@ -60,34 +123,494 @@ impl ImageWriter for PNGWriter {
         data: &[Vec3],
         width: usize,
         height: usize,
-        num_samples: u32,
-    ) {
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        let (width, height) = encoder_dimensions(width, height)?;
         let encoder = PngEncoder::new(out);
-        let mut encodable_data = vec![];
-        for c in data {
-            let average = c / num_samples as f64;
-            let srgb = colors::linear_to_srgb(&average);
-            let (r, g, b) = colors::downscale_to_8bit(&srgb);
-            encodable_data.push(r);
-            encodable_data.push(g);
-            encodable_data.push(b);
+        let (encodable_data, color_type) = if self.sixteen_bits {
+            // The encoder wants them big-endian.
+            let mut encodable_data = Vec::with_capacity(data.len() * 6);
+            for c in data {
+                let (r, g, b) = colors::downscale_to_16bit(c);
+                for channel in [r, g, b] {
+                    encodable_data.extend_from_slice(&channel.to_be_bytes());
+                }
+            }
+            (encodable_data, ColorType::Rgb16)
+        } else {
+            (to_rgb8(data), ColorType::Rgb8)
+        };
+        encoder.encode(&encodable_data, width, height, color_type)
+    }
+}
+
+pub struct JPEGWriter {
+    pub quality: u8,
+}
+
+impl ImageWriter for JPEGWriter {
+    fn write_to(
+        &self,
+        mut out: &mut dyn Write,
+        data: &[Vec3],
+        width: usize,
+        height: usize,
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        let (width, height) = encoder_dimensions(width, height)?;
+        JpegEncoder::new_with_quality(&mut out, self.quality).encode(
+            &to_rgb8(data),
+            width,
+            height,
+            ColorType::Rgb8,
+        )
+    }
+}
+
+pub struct TIFFWriter;
+
+impl ImageWriter for TIFFWriter {
+    fn write_to(
+        &self,
+        out: &mut dyn Write,
+        data: &[Vec3],
+        width: usize,
+        height: usize,
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        let (width, height) = encoder_dimensions(width, height)?;
+        // TIFF needs to seek back into what it wrote, stdout can't.
+        let mut buffer = Cursor::new(vec![]);
+        TiffEncoder::new(&mut buffer).encode(&to_rgb8(data), width, height, ColorType::Rgb8)?;
+        out.write_all(buffer.get_ref())?;
+        Ok(())
+    }
+}
+
+pub struct BMPWriter;
+
+impl ImageWriter for BMPWriter {
+    fn write_to(
+        &self,
+        mut out: &mut dyn Write,
+        data: &[Vec3],
+        width: usize,
+        height: usize,
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        let (width, height) = encoder_dimensions(width, height)?;
+        BmpEncoder::new(&mut out).encode(&to_rgb8(data), width, height, ColorType::Rgb8)
+    }
+}
+
+// Portable float map: a small header, then little-endian 32-bit floats from
+// the bottom row up.
+pub struct PFMWriter;
+
+impl ImageWriter for PFMWriter {
+    fn write_to(
+        &self,
+        out: &mut dyn Write,
+        data: &[Vec3],
+        width: usize,
+        height: usize,
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+        let mut bytes = Vec::with_capacity(data.len() * 12);
+        for row in data.chunks_exact(width).rev() {
+            for c in row {
+                for channel in c.iter() {
+                    bytes.extend_from_slice(&(*channel as f32).to_le_bytes());
+                }
+            }
+        }
+        out.write_all(&bytes)?;
+        Ok(())
+    }
+
+    fn is_linear(&self) -> bool {
+        true
+    }
+}
+
+// Radiance RGBE, which can't store negative values.
+pub struct HDRWriter;
+
+impl ImageWriter for HDRWriter {
+    fn write_to(
+        &self,
+        out: &mut dyn Write,
+        data: &[Vec3],
+        width: usize,
+        height: usize,
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        let encoder = HdrEncoder::new(out);
+        let pixels: Vec<Rgb<f32>> = data
+            .iter()
+            .map(|c| {
+                Rgb([
+                    c.x.max(0.0) as f32,
+                    c.y.max(0.0) as f32,
+                    c.z.max(0.0) as f32,
+                ])
+            })
+            .collect();
+        encoder.encode(&pixels, width, height)
+    }
+
+    fn is_linear(&self) -> bool {
+        true
+    }
+}
+
+// Uncompressed single-part scanline OpenEXR, with R, G and B channels of
+// halves or 32-bit floats.
+pub struct EXRWriter {
+    pub float: bool,
+}
+
+impl EXRWriter {
+    fn write_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    }
+}
+
+impl ImageWriter for EXRWriter {
+    fn write_to(
+        &self,
+        out: &mut dyn Write,
+        data: &[Vec3],
+        width: usize,
+        height: usize,
+    ) -> ImageResult<()> {
+        check_dimensions(data, width, height)?;
+        let (pixel_type, channel_size): (i32, usize) = if self.float { (2, 4) } else { (1, 2) };
+
+        // Magic number, then version 2 without any flag.
+        let mut file = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+        // Channels are listed, and stored, in alphabetical order.
+        let mut channels = vec![];
+        for name in ["B", "G", "R"] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&pixel_type.to_le_bytes());
+            // Not perceptually linear, reserved, x and y sampling.
+            channels.extend_from_slice(&[0, 0, 0, 0]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        let mut window = vec![];
+        for coordinate in [0, 0, width as i32 - 1, height as i32 - 1] {
+            window.extend_from_slice(&coordinate.to_le_bytes());
+        }
+        EXRWriter::write_attribute(&mut file, "channels", "chlist", &channels);
+        EXRWriter::write_attribute(&mut file, "compression", "compression", &[0]);
+        EXRWriter::write_attribute(&mut file, "dataWindow", "box2i", &window);
+        EXRWriter::write_attribute(&mut file, "displayWindow", "box2i", &window);
+        EXRWriter::write_attribute(&mut file, "lineOrder", "lineOrder", &[0]);
+        EXRWriter::write_attribute(&mut file, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+        EXRWriter::write_attribute(&mut file, "screenWindowCenter", "v2f", &[0; 8]);
+        EXRWriter::write_attribute(&mut file, "screenWindowWidth", "float", &1f32.to_le_bytes());
+        file.push(0);
+
+        // One block per scanline, found through a table of their offsets.
+        let line_size = width * 3 * channel_size;
+        let first_line = file.len() + height * 8;
+        for y in 0..height {
+            let offset = first_line + y * (8 + line_size);
+            file.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+        for (y, row) in data.chunks_exact(width).enumerate() {
+            file.extend_from_slice(&(y as i32).to_le_bytes());
+            file.extend_from_slice(&(line_size as i32).to_le_bytes());
+            for channel in [2, 1, 0] {
+                for c in row {
+                    let value = c[channel] as f32;
+                    if self.float {
+                        file.extend_from_slice(&value.to_le_bytes());
+                    } else {
+                        file.extend_from_slice(&f32_to_half(value).to_le_bytes());
+                    }
+                }
+            }
+        }
+        out.write_all(&file)?;
+        Ok(())
+    }
+
+    fn is_linear(&self) -> bool {
+        true
+    }
+}
+
+// IEEE 754 half precision, rounded to nearest even. Values past the largest
+// half become infinities.
+fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    if exponent == 0xff {
+        // Infinities stay so, NaNs keep being NaNs.
+        let nan_bit = if mantissa != 0 { 0x0200 } else { 0 };
+        return sign | 0x7c00 | nan_bit;
+    }
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if half_exponent <= 0 {
+        // Subnormal halves, or zero when too small.
+        if half_exponent < -10 {
+            return sign;
         }
-        encoder
-            .encode(
-                &encodable_data,
-                width.try_into().unwrap(),
-                height.try_into().unwrap(),
-                ColorType::Rgb8,
-            )
-            .unwrap();
+        let mantissa = mantissa | 0x0080_0000;
+        let shift = (14 - half_exponent) as u32;
+        let half_mantissa = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = remainder > halfway || (remainder == halfway && half_mantissa & 1 == 1);
+        return sign | (half_mantissa + round_up as u32) as u16;
+    }
+    let half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    let round_up = remainder > 0x1000 || (remainder == 0x1000 && half & 1 == 1);
+    // Rounding up can carry into the exponent, up to infinity, as it should.
+    sign | (half + round_up as u32) as u16
+}
+
+// --format wins over the output path's extension, PPM is written to stdout
+// without either. Paths without an extension need a --format.
+pub fn output_format<'a>(format: Option<&'a str>, path: Option<&'a str>) -> Option<&'a str> {
+    match (format, path) {
+        (Some(format), _) => Some(format),
+        (None, Some(path)) => Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str()),
+        (None, None) => Some("ppm"),
     }
 }
 
-pub fn guess_output_format(extension: &str) -> Option<Box<dyn ImageWriter>> {
+// Formats are named after their usual extension, with a few variants.
+pub fn guess_output_format(
+    extension: &str,
+    options: &WriterOptions,
+) -> Option<Box<dyn ImageWriter>> {
     let cleaned_extension = extension.to_lowercase();
     match &cleaned_extension as &str {
-        "ppm" => Some(Box::new(PPMWriter {})),
-        "png" => Some(Box::new(PNGWriter {})),
+        "ppm" => Some(Box::new(PPMWriter { binary: false })),
+        "ppm-binary" => Some(Box::new(PPMWriter { binary: true })),
+        "png" => Some(Box::new(PNGWriter {
+            sixteen_bits: false,
+        })),
+        "png16" => Some(Box::new(PNGWriter { sixteen_bits: true })),
+        "jpg" | "jpeg" => Some(Box::new(JPEGWriter {
+            quality: options.jpeg_quality,
+        })),
+        "tif" | "tiff" => Some(Box::new(TIFFWriter {})),
+        "bmp" => Some(Box::new(BMPWriter {})),
+        "pfm" => Some(Box::new(PFMWriter {})),
+        "hdr" => Some(Box::new(HDRWriter {})),
+        "exr" => Some(Box::new(EXRWriter {
+            float: options.exr_float,
+        })),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halves_round_to_nearest_even() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+        assert_eq!(f32_to_half(0.1), 0x2e66);
+        // Halfway between 1 and the next half, then past it.
+        assert_eq!(f32_to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        // Rounding up carries into the exponent.
+        assert_eq!(f32_to_half(2.0 - 2f32.powi(-12)), 0x4000);
+    }
+
+    #[test]
+    fn halves_overflow_to_infinity() {
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        assert_eq!(f32_to_half(65519.0), 0x7bff);
+        assert_eq!(f32_to_half(65520.0), 0x7c00);
+        assert_eq!(f32_to_half(-1e10), 0xfc00);
+        assert_eq!(f32_to_half(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_half(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_half(f32::MAX), 0x7c00);
+    }
+
+    #[test]
+    fn nans_stay_nans() {
+        for nan in [f32::NAN, -f32::NAN, f32::from_bits(0x7f80_0001)] {
+            let half = f32_to_half(nan);
+            assert_eq!(half & 0x7c00, 0x7c00);
+            assert_ne!(half & 0x03ff, 0);
+        }
+    }
+
+    #[test]
+    fn small_values_become_subnormals_or_zero() {
+        assert_eq!(f32_to_half(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_half(2f32.powi(-14) - 2f32.powi(-24)), 0x03ff);
+        assert_eq!(f32_to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_half(-2f32.powi(-24)), 0x8001);
+        // Halfway cases go to the even neighbour.
+        assert_eq!(f32_to_half(2f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_half(3.0 * 2f32.powi(-25)), 0x0002);
+        assert_eq!(f32_to_half(2f32.powi(-25) * 1.0001), 0x0001);
+        // Rounding up the largest subnormal gives the smallest normal.
+        assert_eq!(f32_to_half(2f32.powi(-14) - 2f32.powi(-26)), 0x0400);
+        assert_eq!(f32_to_half(2f32.powi(-26)), 0x0000);
+        assert_eq!(f32_to_half(f32::from_bits(1)), 0x0000);
+        assert_eq!(f32_to_half(-f32::from_bits(1)), 0x8000);
+    }
+
+    #[test]
+    fn pixel_counts_must_match_the_dimensions() {
+        let options = WriterOptions {
+            exr_float: false,
+            jpeg_quality: 90,
+        };
+        let formats = [
+            "ppm",
+            "ppm-binary",
+            "png",
+            "png16",
+            "jpg",
+            "tiff",
+            "bmp",
+            "pfm",
+            "hdr",
+            "exr",
+        ];
+        let data = vec![Vec3::zeros(); 5];
+        for format in formats {
+            let writer = guess_output_format(format, &options).unwrap();
+            // Too few pixels, then too many.
+            for (width, height) in [(2, 3), (2, 2)] {
+                assert!(
+                    matches!(
+                        writer.write_to(&mut vec![], &data, width, height),
+                        Err(ImageError::Parameter(_))
+                    ),
+                    "{}",
+                    format
+                );
+            }
+            assert!(
+                writer.write_to(&mut vec![], &data, 5, 1).is_ok(),
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn empty_pictures_are_rejected() {
+        let options = WriterOptions {
+            exr_float: false,
+            jpeg_quality: 90,
+        };
+        for format in ["ppm", "png", "pfm", "hdr", "exr"] {
+            let writer = guess_output_format(format, &options).unwrap();
+            for (width, height) in [(0, 3), (3, 0), (0, 0)] {
+                assert!(
+                    matches!(
+                        writer.write_to(&mut vec![], &[], width, height),
+                        Err(ImageError::Limits(_))
+                    ),
+                    "{}",
+                    format
+                );
+            }
+        }
+    }
+
+    fn encode(format: &str, options: &WriterOptions) -> Vec<u8> {
+        let writer = guess_output_format(format, options).unwrap();
+        let mut out = vec![];
+        let pixels = [Vec3::new(0.25, 0.5, 1.0); 4];
+        writer.write_to(&mut out, &pixels, 2, 2).unwrap();
+        out
+    }
+
+    #[test]
+    fn formats_are_found_by_extension() {
+        let options = WriterOptions {
+            exr_float: false,
+            jpeg_quality: 90,
+        };
+        let magic_numbers: [(&str, &[u8]); 14] = [
+            ("ppm", b"P3 2 2\n255\n"),
+            ("ppm-binary", b"P6\n2 2\n255\n"),
+            ("PPM", b"P3"),
+            ("png", b"\x89PNG"),
+            ("png16", b"\x89PNG"),
+            ("jpg", &[0xff, 0xd8]),
+            ("JPEG", &[0xff, 0xd8]),
+            ("tif", b"II*\0"),
+            ("tiff", b"II*\0"),
+            ("bmp", b"BM"),
+            ("pfm", b"PF\n2 2\n-1.0\n"),
+            ("hdr", b"#?RADIANCE"),
+            ("exr", &[0x76, 0x2f, 0x31, 0x01]),
+            ("Exr", &[0x76, 0x2f, 0x31, 0x01]),
+        ];
+        for (format, magic) in &magic_numbers {
+            assert!(encode(format, &options).starts_with(magic), "{}", format);
+        }
+        for format in ["", "ppm-ascii", "gif", "png 16", ".png"] {
+            assert!(
+                guess_output_format(format, &options).is_none(),
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn formats_follow_the_options() {
+        let options = |exr_float, jpeg_quality| WriterOptions {
+            exr_float,
+            jpeg_quality,
+        };
+        // The IHDR chunk's bit depth, past the signature, chunk header and size.
+        assert_eq!(encode("png", &options(false, 90))[24], 8);
+        assert_eq!(encode("png16", &options(false, 90))[24], 16);
+        // Floats take twice the room of halves.
+        let halves = encode("exr", &options(false, 90)).len();
+        let floats = encode("exr", &options(true, 90)).len();
+        assert_eq!(floats - halves, 2 * 2 * 3 * 2);
+        assert!(
+            encode("jpg", &options(false, 1)).len() < encode("jpg", &options(false, 100)).len()
+        );
+
+        for format in ["exr", "hdr", "pfm"] {
+            assert!(guess_output_format(format, &options(false, 90))
+                .unwrap()
+                .is_linear());
+        }
+        for format in ["ppm", "png", "jpg", "tiff", "bmp"] {
+            assert!(!guess_output_format(format, &options(false, 90))
+                .unwrap()
+                .is_linear());
+        }
+    }
+}